@@ -18,7 +18,9 @@ impl Visitor for AstPrint {
                 todo!()
                 // format!("(print {})", self.visit_expression(expr))
             }
-            Stmt::Var { name, initializer } => {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
                 todo!() // format!("({} {})", name.lexeme, self.visit_expression(initializer))
             }
             Stmt::Block(statements) => {
@@ -36,15 +38,32 @@ impl Visitor for AstPrint {
             } => {
                 todo!()
             }
-            Stmt::While { cond, body } => {
+            Stmt::While {
+                cond,
+                body,
+                inc,
+                label,
+            } => {
+                todo!()
+            }
+            Stmt::DoWhile { body, cond, label } => {
                 todo!()
             }
-            Stmt::Function { name, params, body } => {
+            Stmt::Break { keyword, label } | Stmt::Continue { keyword, label } => {
+                todo!()
+            }
+            Stmt::Function {
+                name, params, body, ..
+            } => {
                 todo!()
             }
             Stmt::Return { keyword, val } => {
                 todo!()
             }
+            Stmt::Assert { cond, msg, .. } => {
+                todo!()
+            }
+            Stmt::Defer(expr) => format!("(defer {})", self.visit_expression(expr)),
         }
     }
 
@@ -57,8 +76,8 @@ impl Visitor for AstPrint {
                 todo!()
             }
             Expr::Binary { left, op, right } => todo!(),
-            Expr::Variable(name) => todo!(), //format!("{}", name.lexeme),
-            Expr::Assign { name, val } => {
+            Expr::Variable { name, .. } => todo!(), //format!("{}", name.lexeme),
+            Expr::Assign { name, val, .. } => {
                 todo!() // format!("(assign {} {})", name.lexeme, self.visit_expression(val))
             }
             Expr::Logical { left, op, right } => {
@@ -1,6 +1,8 @@
-use crate::value::Value;
+use std::cell::Cell;
 
-use super::{stmt::Stmt, TokenAstInfo};
+use crate::{environment::Local, value::Value};
+
+use super::{stmt::Stmt, types::Signature, TokenAstInfo};
 
 #[derive(Debug, Clone)]
 pub enum Expr {
@@ -15,8 +17,11 @@ pub enum Expr {
         op: TokenAstInfo,
         right: Box<Expr>,
     },
-    /// Contains name of the variable
-    Variable(TokenAstInfo),
+    Variable {
+        name: TokenAstInfo,
+        /// Set by the resolver, `None` for globals
+        local: Cell<Option<Local>>,
+    },
     Logical {
         left: Box<Expr>,
         op: TokenAstInfo,
@@ -25,6 +30,8 @@ pub enum Expr {
     Assign {
         name: TokenAstInfo,
         val: Box<Expr>,
+        /// Set by the resolver, `None` for globals
+        local: Cell<Option<Local>>,
     },
     Call {
         callee: Box<Expr>,
@@ -33,6 +40,23 @@ pub enum Expr {
     },
     Lambda {
         params: Vec<TokenAstInfo>,
+        signature: Signature,
         body: Vec<Stmt>,
+        is_async: bool,
+    },
+    Tuple(Vec<Expr>),
+    Set {
+        brace: TokenAstInfo,
+        items: Vec<Expr>,
+    },
+    Record(Vec<(TokenAstInfo, Expr)>),
+    /// Field access, tuple elements are named by their index
+    Get {
+        object: Box<Expr>,
+        name: TokenAstInfo,
+    },
+    Await {
+        keyword: TokenAstInfo,
+        expr: Box<Expr>,
     },
 }
@@ -1,11 +1,14 @@
 pub mod ast_printer;
 pub mod expr;
 pub mod stmt;
+pub mod types;
 pub mod visitor;
 
 pub use expr::*;
 
-use crate::{token::Token, tokentype::TokenType};
+use std::rc::Rc;
+
+use crate::{interner::intern, token::Token, tokentype::TokenType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TokenAstInfo {
@@ -14,11 +17,29 @@ pub struct TokenAstInfo {
     pos: usize,
     pub line: usize,
     pub kind: TokenType,
-    pub name: Option<String>,
+    pub name: Option<Rc<str>>,
 }
 
 impl TokenAstInfo {
+    pub fn new(pos: usize, line: usize, kind: TokenType, name: Option<Rc<str>>) -> Self {
+        Self {
+            pos,
+            line,
+            kind,
+            name,
+        }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
     pub fn get_name(&self) -> &str {
+        self.interned_name()
+    }
+
+    /// The name shared with every other token of the same identifier
+    pub fn interned_name(&self) -> &Rc<str> {
         self.name.as_ref().expect(
             "Well, that shouldn't happen... ICE Code: 0x2: Tried to get name not from identifier",
         )
@@ -38,7 +59,7 @@ impl std::fmt::Display for TokenAstInfo {
 impl From<&Token> for TokenAstInfo {
     fn from(token: &Token) -> Self {
         let name = match token.r#type {
-            TokenType::Identifier => Some(token.lexeme.clone()),
+            TokenType::Identifier => Some(intern(&token.lexeme)),
             _ => None,
         };
 
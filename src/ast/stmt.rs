@@ -1,4 +1,24 @@
-use super::{Expr, TokenAstInfo};
+use super::{
+    types::{Signature, TypeAnn},
+    Expr, TokenAstInfo,
+};
+
+/// Condition checked on every call of a function
+#[derive(Debug, Clone)]
+pub struct Clause {
+    pub keyword: TokenAstInfo,
+    pub cond: Expr,
+    /// Source text of `cond` for the violation message
+    pub source: String,
+}
+
+/// Preconditions see the parameters, postconditions also see the returned
+/// value as `result`
+#[derive(Debug, Clone, Default)]
+pub struct Contracts {
+    pub requires: Vec<Clause>,
+    pub ensures: Vec<Clause>,
+}
 
 #[derive(Debug, Clone)]
 pub enum Stmt {
@@ -6,6 +26,7 @@ pub enum Stmt {
     Print(Vec<Expr>),
     Var {
         name: TokenAstInfo,
+        tpe: Option<TypeAnn>,
         initializer: Expr,
     },
     Block(Vec<Stmt>),
@@ -14,17 +35,49 @@ pub enum Stmt {
         then: Box<Stmt>,
         else_stmt: Option<Box<Stmt>>,
     },
+    /// Also used for `for` loops, which run `inc` after each iteration
+    /// including the ones left with `continue`, and for `loop`
     While {
         cond: Expr,
         body: Box<Stmt>,
+        inc: Option<Expr>,
+        label: Option<TokenAstInfo>,
+    },
+    DoWhile {
+        body: Box<Stmt>,
+        cond: Expr,
+        label: Option<TokenAstInfo>,
+    },
+    Break {
+        keyword: TokenAstInfo,
+        label: Option<TokenAstInfo>,
+    },
+    Continue {
+        keyword: TokenAstInfo,
+        label: Option<TokenAstInfo>,
     },
     Function {
         name: TokenAstInfo,
         params: Vec<TokenAstInfo>,
+        signature: Signature,
         body: Vec<Stmt>,
+        is_async: bool,
+        /// Callables applied to the function before it is bound, each with
+        /// its '@' token
+        decorators: Vec<(TokenAstInfo, Expr)>,
+        contracts: Contracts,
     },
     Return {
         keyword: TokenAstInfo,
         val: Expr,
     },
+    /// Expression evaluated when the enclosing block or function exits
+    Defer(Expr),
+    Assert {
+        keyword: TokenAstInfo,
+        cond: Expr,
+        msg: Option<Expr>,
+        /// Source text of `cond` for the failure message
+        source: String,
+    },
 }
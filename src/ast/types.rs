@@ -0,0 +1,99 @@
+use crate::value::Value;
+
+use super::TokenAstInfo;
+
+/// Type named in an annotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Any,
+    Nil,
+    Number,
+    String,
+    Bool,
+    Fun,
+    Tuple,
+    Record,
+    Set,
+    Channel,
+    Promise,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "any" => Self::Any,
+            "nil" => Self::Nil,
+            "number" => Self::Number,
+            "string" => Self::String,
+            "bool" => Self::Bool,
+            "fun" => Self::Fun,
+            "tuple" => Self::Tuple,
+            "record" => Self::Record,
+            "set" => Self::Set,
+            "channel" => Self::Channel,
+            "promise" => Self::Promise,
+            _ => return None,
+        })
+    }
+
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Nil => Self::Nil,
+            Value::Number(_) => Self::Number,
+            Value::String(_) => Self::String,
+            Value::Bool(_) => Self::Bool,
+            Value::Fun(_) => Self::Fun,
+            Value::Tuple(_) => Self::Tuple,
+            Value::Record(_) => Self::Record,
+            Value::Set(_) => Self::Set,
+            Value::Channel(_) => Self::Channel,
+            Value::Promise(_) => Self::Promise,
+        }
+    }
+
+    /// `any` on either side matches everything, and `nil` can be stored in a
+    /// variable or parameter of any type
+    pub fn accepts(self, other: Self) -> bool {
+        self == other || self == Self::Any || other == Self::Any || other == Self::Nil
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_write = match self {
+            Self::Any => "any",
+            Self::Nil => "nil",
+            Self::Number => "number",
+            Self::String => "string",
+            Self::Bool => "bool",
+            Self::Fun => "fun",
+            Self::Tuple => "tuple",
+            Self::Record => "record",
+            Self::Set => "set",
+            Self::Channel => "channel",
+            Self::Promise => "promise",
+        };
+
+        write!(f, "{to_write}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeAnn {
+    pub token: TokenAstInfo,
+    pub tpe: Type,
+}
+
+/// Annotated parameter and return types of a function, `None` where the
+/// annotation was omitted
+#[derive(Debug, Clone, Default)]
+pub struct Signature {
+    pub params: Vec<Option<TypeAnn>>,
+    pub ret: Option<TypeAnn>,
+}
+
+impl Signature {
+    pub fn is_annotated(&self) -> bool {
+        self.ret.is_some() || self.params.iter().any(Option::is_some)
+    }
+}
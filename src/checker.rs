@@ -0,0 +1,471 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    ast::{
+        stmt::{Contracts, Stmt},
+        types::{Signature, Type, TypeAnn},
+        visitor::Visitor,
+        Expr, TokenAstInfo,
+    },
+    error::{Error, ParseError},
+    tokentype::TokenType,
+};
+
+/// Static type of an expression. Annotated functions keep their signature, so
+/// calls to them can be checked. Types not coming from an annotation, those of
+/// literals and of unannotated functions, are kept apart, so operators on them
+/// are left for the interpreter to report. So are operators on `any`.
+#[derive(Debug, Clone)]
+pub enum Ty {
+    Simple(Type),
+    Literal(Type),
+    Fun(Rc<FunTy>),
+}
+
+#[derive(Debug)]
+pub struct FunTy {
+    params: Vec<Type>,
+    ret: Type,
+    is_async: bool,
+}
+
+impl Ty {
+    const ANY: Ty = Ty::Simple(Type::Any);
+
+    fn base(&self) -> Type {
+        match self {
+            Ty::Simple(tpe) | Ty::Literal(tpe) => *tpe,
+            Ty::Fun(_) => Type::Fun,
+        }
+    }
+
+    /// Whether the type was inferred without any annotation
+    fn is_inferred(&self) -> bool {
+        matches!(self, Ty::Literal(_) | Ty::Fun(_))
+    }
+}
+
+/// Checks annotated code before it runs. Everything without an annotation
+/// has type `any` and is left to the interpreter, so unannotated scripts pass
+/// unchanged.
+pub struct Checker {
+    scopes: Vec<HashMap<String, Ty>>,
+    /// Annotated return type of the function being checked
+    ret: Option<Type>,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            ret: None,
+        }
+    }
+
+    pub fn check(&mut self, stmts: &[Stmt]) -> Result<(), Vec<Error>> {
+        let mut errors = vec![];
+
+        for stmt in stmts {
+            if let Err(e) = self.visit_statement(stmt) {
+                errors.push(e);
+                self.scopes.truncate(1);
+                self.ret = None;
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn define(&mut self, name: &TokenAstInfo, tpe: Ty) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.get_name().to_string(), tpe);
+        }
+    }
+
+    fn lookup(&self, name: &TokenAstInfo) -> Ty {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name.get_name()))
+            .cloned()
+            .unwrap_or(Ty::ANY)
+    }
+
+    fn expect(&self, token: &TokenAstInfo, expected: Type, actual: &Ty) -> Result<(), Error> {
+        if expected.accepts(actual.base()) {
+            Ok(())
+        } else {
+            Err(type_err(
+                token,
+                format!("Expected {expected} but got {}", actual.base()),
+            ))
+        }
+    }
+
+    fn fun_type(signature: &Signature, is_async: bool) -> Rc<FunTy> {
+        let tpe = |ann: &Option<TypeAnn>| ann.as_ref().map_or(Type::Any, |ann| ann.tpe);
+
+        Rc::new(FunTy {
+            params: signature.params.iter().map(tpe).collect(),
+            ret: tpe(&signature.ret),
+            is_async,
+        })
+    }
+
+    /// Calls are only checked against annotated signatures. Unannotated
+    /// functions may be replaced by ones taking other arguments before the
+    /// calls run.
+    fn fun_value(signature: &Signature, fun: &Rc<FunTy>) -> Ty {
+        if signature.is_annotated() {
+            Ty::Fun(Rc::clone(fun))
+        } else {
+            Ty::Literal(Type::Fun)
+        }
+    }
+
+    fn check_function(
+        &mut self,
+        params: &[TokenAstInfo],
+        fun: &FunTy,
+        contracts: Option<&Contracts>,
+        body: &[Stmt],
+    ) -> Result<(), Error> {
+        let enclosing = self.ret.replace(fun.ret);
+        self.scopes.push(HashMap::new());
+        for (param, tpe) in params.iter().zip(&fun.params) {
+            self.define(param, Ty::Simple(*tpe));
+        }
+
+        if let Some(contracts) = contracts {
+            for clause in &contracts.requires {
+                self.visit_expression(&clause.cond)?;
+            }
+
+            let result = HashMap::from([("result".to_string(), Ty::Simple(fun.ret))]);
+            self.scopes.push(result);
+            for clause in &contracts.ensures {
+                self.visit_expression(&clause.cond)?;
+            }
+            self.scopes.pop();
+        }
+
+        for stmt in body {
+            self.visit_statement(stmt)?;
+        }
+
+        self.scopes.pop();
+        self.ret = enclosing;
+
+        Ok(())
+    }
+
+    fn binary(&self, left: &Ty, op: &TokenAstInfo, right: &Ty) -> Result<Ty, Error> {
+        let res = self.operator(left, op, right);
+        let is_any = |ty: &Ty| ty.base() == Type::Any;
+        if is_any(left) || is_any(right) {
+            Ok(res.unwrap_or(Ty::ANY))
+        } else if left.is_inferred() && right.is_inferred() {
+            Ok(res.map_or(Ty::ANY, |res| match res {
+                Ty::Simple(tpe) => Ty::Literal(tpe),
+                res => res,
+            }))
+        } else {
+            res
+        }
+    }
+
+    fn operator(&self, left: &Ty, op: &TokenAstInfo, right: &Ty) -> Result<Ty, Error> {
+        let (l, r) = (left.base(), right.base());
+        let numbers = |res: Type| {
+            if Type::Number.accepts(l)
+                && Type::Number.accepts(r)
+                && l != Type::Nil
+                && r != Type::Nil
+            {
+                Ok(Ty::Simple(res))
+            } else {
+                Err(type_err(
+                    op,
+                    format!("Operands must be numbers, got {l} and {r}"),
+                ))
+            }
+        };
+
+        match op.kind {
+            TokenType::Minus | TokenType::Slash | TokenType::Star | TokenType::Percent => {
+                numbers(Type::Number)
+            }
+            TokenType::Greater | TokenType::GreaterEq | TokenType::Less | TokenType::LessEq => {
+                numbers(Type::Bool)
+            }
+            TokenType::Plus => match (l, r) {
+                (Type::Number, Type::Number) => Ok(Ty::Simple(Type::Number)),
+                (Type::String, Type::String) => Ok(Ty::Simple(Type::String)),
+                (Type::Any, Type::Number | Type::String | Type::Any) => Ok(Ty::ANY),
+                (Type::Number | Type::String, Type::Any) => Ok(Ty::ANY),
+                _ => Err(type_err(
+                    op,
+                    format!("Operands must be either two strings or two numbers, got {l} and {r}"),
+                )),
+            },
+            _ => Ok(Ty::Simple(Type::Bool)),
+        }
+    }
+}
+
+impl Visitor for Checker {
+    type Output = Result<Ty, Error>;
+
+    fn visit_statement(&mut self, stmt: &Stmt) -> Self::Output {
+        match stmt {
+            Stmt::Expr(expr) | Stmt::Defer(expr) => {
+                self.visit_expression(expr)?;
+            }
+            Stmt::Print(exprs) => {
+                for expr in exprs {
+                    self.visit_expression(expr)?;
+                }
+            }
+            Stmt::Var {
+                name,
+                tpe,
+                initializer,
+            } => {
+                let val = self.visit_expression(initializer)?;
+                let tpe = match tpe {
+                    Some(ann) => {
+                        self.expect(name, ann.tpe, &val)?;
+                        match val {
+                            Ty::Fun(_) if ann.tpe == Type::Fun => val,
+                            _ => Ty::Simple(ann.tpe),
+                        }
+                    }
+                    None => Ty::ANY,
+                };
+                self.define(name, tpe);
+            }
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for stmt in stmts {
+                    self.visit_statement(stmt)?;
+                }
+                self.scopes.pop();
+            }
+            Stmt::If {
+                cond,
+                then,
+                else_stmt,
+            } => {
+                self.visit_expression(cond)?;
+                self.visit_statement(then)?;
+                if let Some(stmt) = else_stmt {
+                    self.visit_statement(stmt)?;
+                }
+            }
+            Stmt::While {
+                cond, body, inc, ..
+            } => {
+                self.visit_expression(cond)?;
+                self.visit_statement(body)?;
+                if let Some(inc) = inc {
+                    self.visit_expression(inc)?;
+                }
+            }
+            Stmt::DoWhile { body, cond, .. } => {
+                self.visit_statement(body)?;
+                self.visit_expression(cond)?;
+            }
+            Stmt::Break { .. } | Stmt::Continue { .. } => (),
+            Stmt::Function {
+                name,
+                params,
+                signature,
+                body,
+                is_async,
+                decorators,
+                contracts,
+            } => {
+                for (_, decorator) in decorators {
+                    self.visit_expression(decorator)?;
+                }
+
+                let fun = Self::fun_type(signature, *is_async);
+                // Decorators may replace the function with anything
+                let tpe = if decorators.is_empty() {
+                    Self::fun_value(signature, &fun)
+                } else {
+                    Ty::ANY
+                };
+                self.define(name, tpe);
+                self.check_function(params, &fun, Some(contracts), body)?;
+            }
+            Stmt::Return { keyword, val } => {
+                let val = self.visit_expression(val)?;
+                if let Some(ret) = self.ret {
+                    self.expect(keyword, ret, &val)?;
+                }
+            }
+            Stmt::Assert { cond, msg, .. } => {
+                self.visit_expression(cond)?;
+                if let Some(msg) = msg {
+                    self.visit_expression(msg)?;
+                }
+            }
+        }
+
+        Ok(Ty::Simple(Type::Nil))
+    }
+
+    fn visit_expression(&mut self, expr: &Expr) -> Self::Output {
+        Ok(match expr {
+            Expr::Literal(val) => Ty::Literal(Type::of(val)),
+            Expr::Grouping(expr) => self.visit_expression(expr)?,
+            Expr::Unary { op, right } => {
+                let right = self.visit_expression(right)?;
+                if op.kind == TokenType::Minus {
+                    let tpe = right.base();
+                    if right.is_inferred() {
+                        Ty::Literal(Type::Number)
+                    } else if !Type::Number.accepts(tpe) || tpe == Type::Nil {
+                        return Err(type_err(op, format!("Operand must be a number, got {tpe}")));
+                    } else {
+                        Ty::Simple(Type::Number)
+                    }
+                } else {
+                    Ty::Simple(Type::Bool)
+                }
+            }
+            Expr::Binary { left, op, right } => {
+                let left = self.visit_expression(left)?;
+                let right = self.visit_expression(right)?;
+                self.binary(&left, op, &right)?
+            }
+            Expr::Variable { name, .. } => self.lookup(name),
+            Expr::Assign { name, val, .. } => {
+                let val = self.visit_expression(val)?;
+                // Only annotated variables and parameters keep their type
+                if let Ty::Simple(tpe) = self.lookup(name) {
+                    self.expect(name, tpe, &val)?;
+                }
+                val
+            }
+            Expr::Logical { left, right, .. } => {
+                let left = self.visit_expression(left)?;
+                let right = self.visit_expression(right)?;
+                if left.base() == right.base() {
+                    left
+                } else {
+                    Ty::ANY
+                }
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                let callee = self.visit_expression(callee)?;
+                let mut arguments = vec![];
+                for arg in args {
+                    arguments.push(self.visit_expression(arg)?);
+                }
+
+                match callee {
+                    Ty::Fun(fun) => {
+                        if fun.params.len() != arguments.len() {
+                            return Err(type_err(
+                                paren,
+                                format!(
+                                    "Expected {} arguments but got {}",
+                                    fun.params.len(),
+                                    arguments.len()
+                                ),
+                            ));
+                        }
+                        for (i, (param, arg)) in fun.params.iter().zip(&arguments).enumerate() {
+                            if !param.accepts(arg.base()) {
+                                return Err(type_err(
+                                    paren,
+                                    format!(
+                                        "Expected {param} for argument {} but got {}",
+                                        i + 1,
+                                        arg.base()
+                                    ),
+                                ));
+                            }
+                        }
+                        // The annotated return type is the type of the awaited result
+                        if fun.is_async {
+                            Ty::Simple(Type::Promise)
+                        } else {
+                            Ty::Simple(fun.ret)
+                        }
+                    }
+                    Ty::Simple(Type::Any | Type::Fun) | Ty::Literal(_) => Ty::ANY,
+                    Ty::Simple(tpe) => {
+                        return Err(type_err(
+                            paren,
+                            format!("Can only call functions, got {tpe}"),
+                        ))
+                    }
+                }
+            }
+            Expr::Lambda {
+                params,
+                signature,
+                body,
+                is_async,
+            } => {
+                let fun = Self::fun_type(signature, *is_async);
+                self.check_function(params, &fun, None, body)?;
+                Self::fun_value(signature, &fun)
+            }
+            Expr::Tuple(items) | Expr::Set { items, .. } => {
+                for item in items {
+                    self.visit_expression(item)?;
+                }
+                Ty::Literal(match expr {
+                    Expr::Tuple(_) => Type::Tuple,
+                    _ => Type::Set,
+                })
+            }
+            Expr::Record(fields) => {
+                for (_, val) in fields {
+                    self.visit_expression(val)?;
+                }
+                Ty::Literal(Type::Record)
+            }
+            Expr::Await { expr, .. } => {
+                self.visit_expression(expr)?;
+                Ty::ANY
+            }
+            Expr::Get { object, name } => {
+                let object = self.visit_expression(object)?.base();
+                if !matches!(object, Type::Any | Type::Tuple | Type::Record) {
+                    return Err(type_err(
+                        name,
+                        format!("Only tuples and records have fields, got {object}"),
+                    ));
+                }
+                Ty::ANY
+            }
+        })
+    }
+}
+
+fn type_err(token: &TokenAstInfo, msg: String) -> Error {
+    ParseError {
+        token: token.clone(),
+        msg: format!("Type error: {msg}"),
+    }
+    .into()
+}
@@ -3,140 +3,163 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc};
 use crate::{
     ast::TokenAstInfo,
     error::{Error, RuntimeError},
+    interner::intern,
     value::Value,
 };
 
+/// Where the resolver found a local variable: how many scopes up from the
+/// current one and its slot in that scope
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Local {
+    pub distance: usize,
+    pub slot: usize,
+}
+
+/// Values of a scope, indexed by the slots the resolver assigned in
+/// declaration order. Globals aren't resolved, so the global scope also maps
+/// their names to slots.
 #[derive(Debug, Clone, Default)]
 pub struct Environment {
     enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Value>,
+    values: Vec<Value>,
+    slots: HashMap<Rc<str>, usize>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
             enclosing: None,
-            values: HashMap::new(),
+            values: vec![],
+            slots: HashMap::new(),
         }
     }
 
     pub fn from(enclosing: &Rc<RefCell<Environment>>) -> Self {
         Self {
             enclosing: Some(Rc::clone(enclosing)),
-            values: HashMap::new(),
+            values: vec![],
+            slots: HashMap::new(),
         }
     }
 
-    pub fn define(&mut self, name: &TokenAstInfo, value: Value) -> Result<(), Error> {
-        if self.enclosing.is_some() && self.values.contains_key(name.name.as_ref().unwrap()) {
-            Err(RuntimeError {
-                token: name.clone(),
-                msg: "You cannot redefine local variables".into(),
-            }
-            .into())
+    /// Binds the next slot of a local scope, or the name of a global. Globals
+    /// may be redefined.
+    pub fn define(&mut self, name: &TokenAstInfo, value: Value) {
+        if self.enclosing.is_some() {
+            self.push(value);
         } else {
-            self.values
-                .insert(name.name.as_ref().unwrap().to_string(), value);
-            Ok(())
+            self.define_global(name.interned_name(), value);
         }
     }
 
+    /// Binds the next slot of a local scope
+    pub fn push(&mut self, value: Value) {
+        self.values.push(value);
+    }
+
     pub fn define_native(&mut self, name: &str, value: Value) {
-        self.values.insert(name.into(), value);
+        self.define_global(&intern(name), value);
     }
 
-    pub fn get(&self, name: &TokenAstInfo) -> Result<Value, Error> {
-        let key = name.name.as_ref().unwrap();
+    fn define_global(&mut self, name: &Rc<str>, value: Value) {
+        match self.slots.get(name) {
+            Some(&slot) => self.values[slot] = value,
+            None => {
+                self.slots.insert(Rc::clone(name), self.values.len());
+                self.values.push(value);
+            }
+        }
+    }
 
-        if let Some(value) = self.values.get(key) {
-            Ok(value.clone())
-        } else if let Some(ref enclosing) = self.enclosing {
-            enclosing.borrow().get(name)
-        } else {
-            Err(RuntimeError {
-                msg: format!("Undefined variable '{key}'"),
+    /// Looks a global up by name
+    pub fn get(&self, name: &TokenAstInfo) -> Result<Value, Error> {
+        match self.slots.get(name.get_name()) {
+            Some(&slot) => Ok(self.values[slot].clone()),
+            None => Err(RuntimeError {
+                msg: format!("Undefined variable '{}'", name.get_name()),
                 token: name.clone(),
             }
-            .into())
+            .into()),
         }
     }
 
-    pub fn get_at(&self, distance: usize, name: &str) -> Value {
-        if distance == 0 {
-            return self.values.get(name).cloned().unwrap();
+    pub fn get_at(&self, local: Local) -> Value {
+        if local.distance == 0 {
+            return self.slot(local.slot).clone();
         }
 
-        self.ancestor(distance)
+        self.ancestor(local.distance)
             .borrow()
-            .values
-            .get(name)
-            .cloned()
-            .expect(&format!(
-                "Well, that shouldn't happen... ICE Code 0x4: Undefined variable '{name}'"
-            ))
-    }
-
-    pub fn assign_at(
-        &mut self,
-        distance: usize,
-        name: &TokenAstInfo,
-        val: &Value,
-    ) -> Result<(), Error> {
-        if distance == 0 {
-            match self
-                .values
-                .get_mut(name.get_name())
-                .map(|v| *v = val.clone())
-            {
-                Some(_) => Ok(()),
-                None => Err(RuntimeError {
-                    token: name.clone(),
-                    msg: format!("Undefined variable '{}'", name.get_name()),
-                }
-                .into()),
-            }
+            .slot(local.slot)
+            .clone()
+    }
+
+    pub fn assign_at(&mut self, local: Local, val: &Value) {
+        if local.distance == 0 {
+            *self.slot_mut(local.slot) = val.clone();
         } else {
-            Ok(self.ancestor(distance).borrow_mut().assign(name, val)?)
+            *self
+                .ancestor(local.distance)
+                .borrow_mut()
+                .slot_mut(local.slot) = val.clone();
         }
     }
 
+    pub fn enclosing(&self) -> Option<&Rc<RefCell<Environment>>> {
+        self.enclosing.as_ref()
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// Drops the values of a scope the collector found unreachable
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.slots.clear();
+    }
+
+    fn slot(&self, slot: usize) -> &Value {
+        self.values.get(slot).unwrap_or_else(|| {
+            panic!("Well, that shouldn't happen... ICE Code 0x4: Undefined slot '{slot}'")
+        })
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> &mut Value {
+        self.values.get_mut(slot).unwrap_or_else(|| {
+            panic!("Well, that shouldn't happen... ICE Code 0x4: Undefined slot '{slot}'")
+        })
+    }
+
     pub fn ancestor(&self, distance: usize) -> Rc<RefCell<Environment>> {
         let parent = self.enclosing.clone()
-            .expect(&format!("Well, that shouldn't happen... ICE Code: 0x3: No enclosing environment at '{distance}'"));
+            .unwrap_or_else(|| panic!("Well, that shouldn't happen... ICE Code: 0x3: No enclosing environment at '{distance}'"));
 
         let mut env = Rc::clone(&parent);
         for i in 1..distance {
-            let parent = env.borrow().enclosing.clone().expect(&format!(
-                "Well, that shouldn't happen... ICE Code: 0x3: No enclosing environment at '{i}'"
-            ));
+            let parent = env.borrow().enclosing.clone().unwrap_or_else(|| {
+                panic!(
+                    "Well, that shouldn't happen... ICE Code: 0x3: No enclosing environment at '{i}'"
+                )
+            });
             env = Rc::clone(&parent);
         }
 
         env
     }
 
+    /// Assigns an existing global
     pub fn assign(&mut self, name: &TokenAstInfo, value: &Value) -> Result<(), Error> {
-        let key = name.name.as_ref().unwrap();
-        if self.values.contains_key(key) {
-            self.values.insert(key.clone(), value.clone());
-            Ok(())
-        } else if let Some(ref enclosing) = self.enclosing {
-            enclosing.borrow_mut().assign(name, value)
-        } else {
-            Err(RuntimeError {
+        match self.slots.get(name.get_name()) {
+            Some(&slot) => {
+                self.values[slot] = value.clone();
+                Ok(())
+            }
+            None => Err(RuntimeError {
                 msg: format!("Undefined variable '{}'", name),
                 token: name.clone(),
             }
-            .into())
-        }
-    }
-}
-
-impl From<HashMap<String, Value>> for Environment {
-    fn from(globals: HashMap<String, Value>) -> Self {
-        Self {
-            enclosing: None,
-            values: globals,
+            .into()),
         }
     }
 }
@@ -1,14 +1,22 @@
 use std::fmt::Display;
 
-use crate::{ast::TokenAstInfo, value::Value};
+use crate::{ast::TokenAstInfo, function::TailCall, value::Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Error {
     ScannerError(ScanError),
     ParseError(ParseError),
     RuntimeError(RuntimeError),
     NativeCallError(String),
+    /// Execution stopped by a limit set on the interpreter
+    LimitExceeded(Limit),
     Return(Value),
+    /// `return` of a call, made once the returning function is left
+    TailCall(Box<TailCall>),
+    /// `break` unwinding to the loop with the given label, or the innermost one
+    Break(Option<String>),
+    /// `continue` unwinding to the loop with the given label, or the innermost one
+    Continue(Option<String>),
 }
 
 impl From<RuntimeError> for Error {
@@ -34,16 +42,40 @@ impl Display for Error {
         match self {
             Self::RuntimeError(re) => write!(f, "{re}"),
             Self::Return(v) => write!(f, "{v}"),
+            Self::TailCall(_) => write!(f, "tail call"),
             Self::ParseError(pe) => write!(f, "{pe}"),
             Self::NativeCallError(msg) => write!(f, "{msg}"),
+            Self::LimitExceeded(limit) => write!(f, "{limit}"),
             Self::ScannerError(se) => write!(f, "{se}"),
+            Self::Break(_) => write!(f, "break"),
+            Self::Continue(_) => write!(f, "continue"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-#[derive(Debug)]
+/// Bound on the resources a script may use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// Statements and expressions evaluated, or VM instructions run
+    Steps,
+    Deadline,
+    /// Heap memory allocated while running
+    Memory,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Steps => write!(f, "Step budget exhausted"),
+            Self::Deadline => write!(f, "Deadline exceeded"),
+            Self::Memory => write!(f, "Memory limit exceeded"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ParseError {
     pub token: TokenAstInfo,
     pub msg: String,
@@ -57,7 +89,7 @@ impl Display for ParseError {
 
 impl std::error::Error for ParseError {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ScanError {
     pub msg: String,
     pub line: usize,
@@ -69,7 +101,7 @@ impl Display for ScanError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RuntimeError {
     pub token: TokenAstInfo,
     pub msg: String,
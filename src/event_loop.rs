@@ -0,0 +1,188 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ast::TokenAstInfo,
+    error::{Error, Limit, RuntimeError},
+    fiber,
+    function::Function,
+    interpreter::Interpreter,
+    lox_callable::Callable,
+    value::Value,
+};
+
+#[derive(Debug)]
+enum PromiseState {
+    Pending,
+    Settled(Result<Value, Error>),
+}
+
+/// Result of an `async` call or a host operation, available once settled
+#[derive(Debug)]
+pub struct Promise {
+    state: RefCell<PromiseState>,
+    /// Set once the promise is awaited, rejections that were never awaited
+    /// are reported when the event loop finishes
+    handled: Cell<bool>,
+}
+
+impl Default for Promise {
+    fn default() -> Self {
+        Self {
+            state: RefCell::new(PromiseState::Pending),
+            handled: Cell::new(false),
+        }
+    }
+}
+
+impl Promise {
+    pub fn result(&self) -> Option<Result<Value, Error>> {
+        match &*self.state.borrow() {
+            PromiseState::Pending => None,
+            PromiseState::Settled(res) => Some(res.clone()),
+        }
+    }
+
+    pub fn mark_handled(&self) {
+        self.handled.set(true);
+    }
+}
+
+/// Promises are compared by identity
+impl PartialEq for Promise {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+enum TimerAction {
+    Call(Function, TokenAstInfo),
+    Resolve(Rc<Promise>),
+}
+
+struct Timer {
+    due: Instant,
+    action: TimerAction,
+}
+
+/// Pending timers and settled promises of one interpreter, shared by all of
+/// its fibers
+#[derive(Default)]
+pub struct EventLoop {
+    /// Kept in the order they fire, timers due at the same time fire in the
+    /// order they were set
+    timers: Vec<Timer>,
+    rejected: Vec<Rc<Promise>>,
+}
+
+impl EventLoop {
+    pub fn is_idle(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    fn add_timer(&mut self, ms: f64, action: TimerAction) -> Result<(), Error> {
+        let due = Duration::try_from_secs_f64(ms / 1000.)
+            .ok()
+            .and_then(|delay| Instant::now().checked_add(delay))
+            .ok_or_else(|| Error::NativeCallError("Delay is too long".into()))?;
+        let at = self.timers.partition_point(|t| t.due <= due);
+        self.timers.insert(at, Timer { due, action });
+
+        Ok(())
+    }
+
+    /// Rejections nobody awaited since the last call
+    pub fn take_unhandled(&mut self) -> Vec<Error> {
+        self.rejected
+            .drain(..)
+            .filter(|promise| !promise.handled.get())
+            .filter_map(|promise| promise.result()?.err())
+            .map(|err| match err {
+                Error::RuntimeError(RuntimeError { token, msg }) => RuntimeError {
+                    token,
+                    msg: format!("Unhandled promise rejection: {msg}"),
+                }
+                .into(),
+                err => err,
+            })
+            .collect()
+    }
+}
+
+/// Settles the promise and wakes the fibers waiting on it
+pub fn settle(interpreter: &Interpreter, promise: &Rc<Promise>, res: Result<Value, Error>) {
+    if res.is_err() {
+        interpreter
+            .event_loop()
+            .borrow_mut()
+            .rejected
+            .push(Rc::clone(promise));
+    }
+    *promise.state.borrow_mut() = PromiseState::Settled(res);
+    interpreter.scheduler().unblock_all();
+}
+
+/// Runs `fun` in a new fiber once `ms` milliseconds have passed
+pub fn set_timeout(
+    interpreter: &Interpreter,
+    fun: Function,
+    paren: TokenAstInfo,
+    ms: f64,
+) -> Result<(), Error> {
+    interpreter
+        .event_loop()
+        .borrow_mut()
+        .add_timer(ms, TimerAction::Call(fun, paren))
+}
+
+/// Promise resolved with `nil` once `ms` milliseconds have passed
+pub fn sleep(interpreter: &Interpreter, ms: f64) -> Result<Rc<Promise>, Error> {
+    let promise = Rc::new(Promise::default());
+    interpreter
+        .event_loop()
+        .borrow_mut()
+        .add_timer(ms, TimerAction::Resolve(Rc::clone(&promise)))?;
+
+    Ok(promise)
+}
+
+/// Waits for the earliest timer and runs it. Returns `false` when there are
+/// no timers left, fails if the deadline of the interpreter passes first.
+pub fn fire_next(interpreter: &Interpreter) -> Result<bool, Error> {
+    let timer = {
+        let mut event_loop = interpreter.event_loop().borrow_mut();
+        if event_loop.timers.is_empty() {
+            return Ok(false);
+        }
+        event_loop.timers.remove(0)
+    };
+
+    let now = Instant::now();
+    if timer.due > now {
+        match interpreter.deadline() {
+            Some(deadline) if deadline < timer.due => {
+                thread::sleep(deadline.saturating_duration_since(now));
+                return Err(Error::LimitExceeded(Limit::Deadline));
+            }
+            _ => thread::sleep(timer.due - now),
+        }
+    }
+
+    match timer.action {
+        TimerAction::Call(fun, paren) => {
+            let at = paren.clone();
+            fiber::spawn(interpreter, &paren, move |interpreter, started| {
+                if let Err(e) = started.and_then(|()| fun.call(interpreter, &at, &[])) {
+                    interpreter.fiber_error(e);
+                }
+            });
+        }
+        TimerAction::Resolve(promise) => settle(interpreter, &promise, Ok(Value::Nil)),
+    }
+
+    Ok(true)
+}
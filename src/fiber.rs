@@ -0,0 +1,448 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+    thread::{self, Thread},
+};
+
+use crate::{
+    ast::TokenAstInfo,
+    error::{Error, RuntimeError},
+    event_loop::{self, Promise},
+    interpreter::Interpreter,
+    memory::Metering,
+    value::Value,
+};
+
+/// The interpreter executes through Rust recursion, so every spawned fiber
+/// gets an OS thread whose stack holds its suspended state. The main script
+/// gets as much.
+pub const FIBER_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Most fibers started and not done yet, the script included. Each of them
+/// holds a thread.
+const MAX_FIBERS: usize = 1024;
+
+/// Fiber threads kept around for the next fibers once theirs are done
+const MAX_IDLE: usize = 16;
+
+thread_local! {
+    static ON_FIBER_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether this thread was started for a spawned fiber
+pub fn is_fiber_thread() -> bool {
+    ON_FIBER_THREAD.get()
+}
+
+/// Unbounded queue of values passed between fibers
+#[derive(Debug, Default)]
+pub struct Channel {
+    queue: RefCell<VecDeque<Value>>,
+}
+
+impl Channel {
+    pub fn send(&self, val: Value) {
+        self.queue.borrow_mut().push_back(val);
+    }
+
+    pub fn try_receive(&self) -> Option<Value> {
+        self.queue.borrow_mut().pop_front()
+    }
+}
+
+/// Channels are compared by identity
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Fiber holding the baton, the only one allowed to run
+    current: usize,
+    /// Every other live fiber, in the order they get the baton
+    ready: VecDeque<usize>,
+    /// Waiting fibers, cleared whenever a value is sent or a promise settled
+    blocked: HashSet<usize>,
+    /// Threads of the fibers waiting for the baton, each is woken alone
+    parked: HashMap<usize, Thread>,
+    /// Fibers that never ran, they only get a thread once they do
+    pending: HashMap<usize, Handoff>,
+    /// Fibers handed the baton for the first time, waiting for a thread
+    jobs: Vec<(usize, Handoff)>,
+    /// Fiber threads without a fiber, parked until a job comes
+    idle: Vec<Thread>,
+    /// Fiber threads alive, idle ones included
+    threads: usize,
+    last_id: usize,
+}
+
+impl State {
+    /// Returns the fiber along with its handoff if it never ran, it then
+    /// needs a thread
+    fn hand_to(&mut self, fiber: usize) -> Option<(usize, Handoff)> {
+        self.current = fiber;
+        if let Some(thread) = self.parked.get(&fiber) {
+            thread.unpark();
+        }
+        Some((fiber, self.pending.remove(&fiber)?))
+    }
+}
+
+/// Round-robin scheduler for cooperative fibers. Fiber `0` is the thread
+/// that runs the script. Spawned fibers get a thread when the baton is first
+/// handed to them, the one of a fiber that just finished when possible, and
+/// keep it until they are done.
+#[derive(Default)]
+pub struct Scheduler {
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Parks the thread until the baton is handed to `fiber`. An unpark
+    /// coming between unlocking and parking makes `park` return at once.
+    fn wait_turn<'a>(&'a self, mut state: MutexGuard<'a, State>, fiber: usize) {
+        while state.current != fiber {
+            state.parked.insert(fiber, thread::current());
+            drop(state);
+            thread::park();
+            state = self.lock();
+        }
+        state.parked.remove(&fiber);
+    }
+
+    /// Hands the baton to the next ready fiber and returns once it comes back
+    pub fn yield_now(self: &Arc<Self>, fiber: usize) {
+        let mut state = self.lock();
+        state.ready.push_back(fiber);
+        let next = state.ready.pop_front().unwrap();
+        if let Some(new) = state.hand_to(next) {
+            drop(state);
+            self.start(new);
+            state = self.lock();
+        }
+
+        self.wait_turn(state, fiber);
+    }
+
+    /// Hands the baton over for good. Returns the next fiber if it never
+    /// ran, for the thread of this one to run it.
+    fn finish(&self, fiber: usize) -> Option<(usize, Handoff)> {
+        let mut state = self.lock();
+        state.blocked.remove(&fiber);
+        let next = state.ready.pop_front()?;
+        state.hand_to(next)
+    }
+
+    /// Gives a fiber that was just handed the baton for the first time a
+    /// thread: an idle one, or a new one. When there's none, its task learns
+    /// why on this thread, and the baton moves on.
+    fn start(self: &Arc<Self>, new: (usize, Handoff)) {
+        let mut next = Some(new);
+        while let Some((fiber, handoff)) = next {
+            let mut state = self.lock();
+            state.jobs.push((fiber, handoff));
+            if let Some(thread) = state.idle.pop() {
+                thread.unpark();
+                return;
+            }
+
+            let msg = if state.threads + 1 >= MAX_FIBERS {
+                format!("Too many fibers, at most {MAX_FIBERS} can be running at once")
+            } else {
+                state.threads += 1;
+                drop(state);
+                let scheduler = Arc::clone(self);
+                let started = thread::Builder::new()
+                    .stack_size(FIBER_STACK_SIZE)
+                    .spawn(move || scheduler.work());
+                state = self.lock();
+                match started {
+                    Ok(_) => return,
+                    Err(e) => {
+                        state.threads -= 1;
+                        format!("Failed to start a fiber: {e}")
+                    }
+                }
+            };
+
+            // A thread done with its fiber may have taken the job meanwhile
+            let Some(at) = state.jobs.iter().position(|(f, _)| *f == fiber) else {
+                return;
+            };
+            let (fiber, handoff) = state.jobs.swap_remove(at);
+            drop(state);
+            let error = RuntimeError {
+                token: handoff.paren.clone(),
+                msg,
+            };
+            let finish = Finish {
+                scheduler: self,
+                fiber,
+            };
+            handoff.run(Err(error.into()));
+            next = finish.done();
+        }
+    }
+
+    /// Body of a fiber thread: runs fibers until it has been idle and is let
+    /// go, or enough others are idle already
+    fn work(self: Arc<Self>) {
+        ON_FIBER_THREAD.set(true);
+        let _thread = Leave(&self);
+
+        let mut next = self.next_job();
+        while let Some((fiber, handoff)) = next {
+            let finish = Finish {
+                scheduler: &self,
+                fiber,
+            };
+            handoff.run(Ok(()));
+            next = finish.done().or_else(|| self.next_job());
+        }
+    }
+
+    /// Parks a fiber thread until a fiber needs it. Returns `None` when the
+    /// thread isn't needed anymore.
+    fn next_job(&self) -> Option<(usize, Handoff)> {
+        let mut state = self.lock();
+        if state.jobs.is_empty() && state.idle.len() >= MAX_IDLE {
+            return None;
+        }
+
+        let thread = thread::current();
+        state.idle.push(thread.clone());
+        loop {
+            let idle = state.idle.iter().position(|t| t.id() == thread.id());
+            if let Some(job) = state.jobs.pop() {
+                if let Some(at) = idle {
+                    state.idle.swap_remove(at);
+                }
+                return Some(job);
+            }
+            // Let go, or woken for a job another thread took
+            idle?;
+
+            drop(state);
+            thread::park();
+            state = self.lock();
+        }
+    }
+
+    /// Ends the idle fiber threads, once no fiber is left to run
+    fn let_go(&self) {
+        for thread in self.lock().idle.drain(..) {
+            thread.unpark();
+        }
+    }
+
+    /// Marks the fiber as waiting. Returns whether any other fiber can still
+    /// make progress.
+    fn block(&self, fiber: usize) -> bool {
+        let mut state = self.lock();
+        state.blocked.insert(fiber);
+        !state.ready.iter().all(|f| state.blocked.contains(f))
+    }
+
+    fn unblock(&self, fiber: usize) {
+        self.lock().blocked.remove(&fiber);
+    }
+
+    /// Called whenever something a fiber could be waiting for happens
+    pub fn unblock_all(&self) {
+        self.lock().blocked.clear();
+    }
+
+    fn has_others(&self) -> bool {
+        !self.lock().ready.is_empty()
+    }
+}
+
+/// Hands the baton over for good once a fiber is done, even when it panics
+struct Finish<'a> {
+    scheduler: &'a Arc<Scheduler>,
+    fiber: usize,
+}
+
+impl Finish<'_> {
+    /// Returns the next fiber if it never ran, for this thread to run it
+    fn done(self) -> Option<(usize, Handoff)> {
+        let next = self.scheduler.finish(self.fiber);
+        mem::forget(self);
+        next
+    }
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        if let Some(new) = self.scheduler.finish(self.fiber) {
+            self.scheduler.start(new);
+        }
+    }
+}
+
+/// Counts a fiber thread out when it ends, even when it panics
+struct Leave<'a>(&'a Scheduler);
+
+impl Drop for Leave<'_> {
+    fn drop(&mut self) {
+        self.0.lock().threads -= 1;
+    }
+}
+
+/// What a new fiber runs. It gets the error instead of running when no
+/// thread could be found for the fiber.
+type Task = Box<dyn FnOnce(&mut Interpreter, Result<(), Error>)>;
+
+/// Moves the interpreter and task of a new fiber to its thread. They are
+/// full of `Rc`s shared with other fibers, which is sound because:
+/// - the thread only touches them while it holds the baton, which is passed
+///   through the scheduler's mutex, so no two threads do at once
+/// - both are dropped before the fiber hands the baton over for good
+/// - nothing shared is kept in thread locals, whose destructors would run
+///   after that, so fiber threads must not intern strings (checked in debug
+///   builds)
+struct Handoff {
+    interpreter: Interpreter,
+    /// Where the fiber was spawned, blamed when it can't get a thread
+    paren: TokenAstInfo,
+    task: Task,
+}
+
+// SAFETY: see above, the payload is only used while holding the baton
+unsafe impl Send for Handoff {}
+
+impl Handoff {
+    /// Runs the task, everything shared with other fibers is dropped once
+    /// this returns
+    fn run(self, started: Result<(), Error>) {
+        let Self {
+            mut interpreter,
+            task,
+            ..
+        } = self;
+        let _metering = Metering::start(interpreter.meter());
+        task(&mut interpreter, started);
+    }
+}
+
+/// Starts `task` in a new fiber. It first runs when the current fiber yields,
+/// and fails at `paren` if no thread can be found for it then.
+pub fn spawn<F>(interpreter: &Interpreter, paren: &TokenAstInfo, task: F)
+where
+    F: FnOnce(&mut Interpreter, Result<(), Error>) + 'static,
+{
+    let mut state = interpreter.scheduler().lock();
+    state.last_id += 1;
+    let fiber = state.last_id;
+    state.ready.push_back(fiber);
+    let handoff = Handoff {
+        interpreter: interpreter.fork(fiber),
+        paren: paren.clone(),
+        task: Box::new(task),
+    };
+    state.pending.insert(fiber, handoff);
+}
+
+pub fn send(interpreter: &Interpreter, channel: &Channel, val: Value) {
+    channel.send(val);
+    interpreter.scheduler().unblock_all();
+}
+
+/// Lets the other fibers run, and fires timers once all of them wait too,
+/// until `poll` produces a value. Returns `None` when nothing is left that
+/// could make it produce one.
+fn wait<T>(
+    interpreter: &Interpreter,
+    mut poll: impl FnMut() -> Option<T>,
+) -> Result<Option<T>, Error> {
+    let scheduler = interpreter.scheduler();
+    let fiber = interpreter.fiber();
+
+    loop {
+        if let Some(val) = poll() {
+            scheduler.unblock(fiber);
+            return Ok(Some(val));
+        }
+
+        if scheduler.block(fiber) {
+            scheduler.yield_now(fiber);
+        } else {
+            let fired = event_loop::fire_next(interpreter);
+            if !matches!(fired, Ok(true)) {
+                scheduler.unblock(fiber);
+                return fired.map(|_| None);
+            }
+        }
+    }
+}
+
+/// Switches to other fibers until a value arrives on the channel
+pub fn receive(
+    interpreter: &Interpreter,
+    paren: &TokenAstInfo,
+    channel: &Channel,
+) -> Result<Value, Error> {
+    wait(interpreter, || channel.try_receive())?.ok_or_else(|| {
+        RuntimeError {
+            token: paren.clone(),
+            msg: "Deadlock: every fiber is waiting on a channel".into(),
+        }
+        .into()
+    })
+}
+
+/// Switches to other fibers until the promise is settled
+pub fn await_promise(
+    interpreter: &Interpreter,
+    keyword: &TokenAstInfo,
+    promise: &Promise,
+) -> Result<Value, Error> {
+    promise.mark_handled();
+    wait(interpreter, || promise.result())?.unwrap_or_else(|| {
+        Err(RuntimeError {
+            token: keyword.clone(),
+            msg: "Deadlock: awaited promise can never be settled".into(),
+        }
+        .into())
+    })
+}
+
+/// Runs the other fibers and pending timers until all of them are done, or
+/// the deadline of the interpreter passes while waiting for a timer
+pub fn run_event_loop(interpreter: &Interpreter) -> Result<(), Error> {
+    let scheduler = interpreter.scheduler();
+    let fiber = interpreter.fiber();
+
+    let mut res = Ok(());
+    while scheduler.has_others() || !interpreter.event_loop().borrow().is_idle() {
+        // Fibers stuck on each other get the baton to report their deadlock
+        if scheduler.block(fiber) {
+            scheduler.yield_now(fiber);
+            continue;
+        }
+        match event_loop::fire_next(interpreter) {
+            Ok(true) => (),
+            Ok(false) => scheduler.yield_now(fiber),
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        }
+    }
+    scheduler.unblock(fiber);
+    scheduler.let_go();
+
+    for e in interpreter.event_loop().borrow_mut().take_unhandled() {
+        interpreter.fiber_error(e);
+    }
+
+    res
+}
@@ -1,15 +1,30 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-    ast::{stmt::Stmt, TokenAstInfo},
+    ast::{
+        stmt::{Clause, Contracts, Stmt},
+        types::{Signature, Type, TypeAnn},
+        TokenAstInfo,
+    },
     environment::Environment,
-    error::Error,
-    interpreter::Interpreter,
+    error::{Error, RuntimeError},
+    event_loop::{self, Promise},
+    fiber,
+    interpreter::{is_truthy, Interpreter},
     lox_callable::Callable,
     value::Value,
+    vm::{self, chunk::Closure},
 };
 
-type NativeFun = Box<fn(&[Value]) -> Result<Value, Error>>;
+/// Call in tail position, see `Function::call`
+#[derive(Debug, Clone)]
+pub struct TailCall {
+    pub fun: Function,
+    pub paren: TokenAstInfo,
+    pub args: Vec<Value>,
+}
+
+type NativeFun = Box<fn(&mut Interpreter, &TokenAstInfo, &[Value]) -> Result<Value, Error>>;
 
 #[derive(Clone)]
 pub enum Function {
@@ -19,67 +34,184 @@ pub enum Function {
     },
     LoxFun {
         name: TokenAstInfo,
-        params: Vec<TokenAstInfo>,
-        body: Vec<Stmt>,
+        params: Rc<[TokenAstInfo]>,
+        signature: Rc<Signature>,
+        body: Rc<[Stmt]>,
         closure: Rc<RefCell<Environment>>,
+        is_async: bool,
+        contracts: Rc<Contracts>,
     },
     LoxLambda {
-        params: Vec<TokenAstInfo>,
-        body: Vec<Stmt>,
+        params: Rc<[TokenAstInfo]>,
+        signature: Rc<Signature>,
+        body: Rc<[Stmt]>,
         closure: Rc<RefCell<Environment>>,
+        is_async: bool,
     },
+    /// Function compiled for the VM
+    Closure(Rc<Closure>),
 }
 
 impl Callable<Result<Value, Error>> for Function {
-    fn call(&self, interpreter: &mut Interpreter, args: &[Value]) -> Result<Value, Error> {
+    /// Calls in tail position are made here once the function returned them,
+    /// so they don't grow the native stack
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &TokenAstInfo,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        let res = self.enter(interpreter, paren, args);
+        trampoline(interpreter, res)
+    }
+
+    fn arity(&self) -> usize {
         match self {
-            Self::Native { body, .. } => Ok(body(args)?),
+            Self::Native { arity, .. } => *arity,
+            Self::LoxFun { params, .. } => params.len(),
+            Self::LoxLambda { params, .. } => params.len(),
+            Self::Closure(closure) => closure.proto.arity(),
+        }
+    }
+}
+
+fn trampoline(
+    interpreter: &mut Interpreter,
+    mut res: Result<Value, Error>,
+) -> Result<Value, Error> {
+    while let Err(Error::TailCall(call)) = res {
+        res = call.fun.enter(interpreter, &call.paren, &call.args);
+    }
+
+    res
+}
+
+impl Function {
+    /// Runs the function, up to the call it returns in tail position
+    fn enter(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &TokenAstInfo,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        let (params, signature, is_async) = match self {
+            Self::Native { body, .. } => return body(interpreter, paren, args),
             Self::LoxFun {
                 params,
-                body,
-                closure,
+                signature,
+                is_async,
                 ..
-            } => {
-                let env = Rc::new(RefCell::new(Environment::from(closure)));
-                for (param, arg) in params.iter().zip(args.iter()) {
-                    env.borrow_mut().define(param, arg.clone())?;
-                }
-
-                match interpreter.execute_block(body, env) {
-                    Ok(v) => Ok(v),
-                    Err(e) => match e {
-                        Error::Return(v) => Ok(v),
-                        re => Err(re),
-                    },
-                }
             }
-            Self::LoxLambda {
+            | Self::LoxLambda {
                 params,
-                body,
-                closure,
-            } => {
-                let env = Rc::new(RefCell::new(Environment::from(closure)));
-                for (param, arg) in params.iter().zip(args.iter()) {
-                    env.borrow_mut().define(param, arg.clone())?;
-                }
-
-                match interpreter.execute_block(body, env) {
-                    Ok(v) => Ok(v),
-                    Err(e) => match e {
-                        Error::Return(v) => Ok(v),
-                        re => Err(re),
-                    },
-                }
+                signature,
+                is_async,
+                ..
+            } => (&params[..], signature.as_ref(), *is_async),
+            Self::Closure(closure) => (
+                &closure.proto.params[..],
+                &closure.proto.signature,
+                closure.proto.is_async,
+            ),
+        };
+
+        if interpreter.type_hints() {
+            for ((param, tpe), arg) in params.iter().zip(&signature.params).zip(args) {
+                check_param(paren, param, tpe, arg)?;
             }
         }
+
+        if !is_async {
+            return self.execute(interpreter, paren, args);
+        }
+
+        let promise = Rc::new(Promise::default());
+        let (fun, at, args) = (self.clone(), paren.clone(), args.to_vec());
+        let settled = Rc::clone(&promise);
+        fiber::spawn(interpreter, paren, move |interpreter, started| {
+            let res = started.and_then(|()| fun.execute(interpreter, &at, &args));
+            let res = trampoline(interpreter, res);
+            event_loop::settle(interpreter, &settled, res);
+        });
+
+        Ok(Value::Promise(promise))
     }
 
-    fn arity(&self) -> usize {
-        match self {
-            Self::Native { arity, .. } => *arity,
-            Self::LoxFun { params, .. } => params.len(),
-            Self::LoxLambda { params, .. } => params.len(),
+    /// Runs the body of a Lox function in the calling fiber
+    fn execute(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &TokenAstInfo,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        if let Self::Closure(closure) = self {
+            return vm::call(interpreter, closure, paren, args);
         }
+
+        let (Self::LoxFun {
+            params,
+            signature,
+            body,
+            closure,
+            ..
+        }
+        | Self::LoxLambda {
+            params,
+            signature,
+            body,
+            closure,
+            ..
+        }) = self
+        else {
+            unreachable!(
+                "Well, that shouldn't happen... ICE Code: 0x8: Executing a native function"
+            )
+        };
+
+        let env = Rc::new(RefCell::new(Environment::from(closure)));
+        for (param, arg) in params.iter().zip(args) {
+            env.borrow_mut().define(param, arg.clone());
+        }
+
+        let contracts = match self {
+            Self::LoxFun {
+                name, contracts, ..
+            } if interpreter.assertions() => Some((name.get_name(), contracts)),
+            _ => None,
+        };
+
+        if let Some((name, contracts)) = contracts {
+            for clause in &contracts.requires {
+                // Callers are to blame for broken preconditions
+                check_clause(interpreter, &env, paren, clause, "Precondition", name)?;
+            }
+        }
+
+        // Results that are checked can't be left to a tail call
+        let tail_calls = contracts.is_none_or(|(_, contracts)| contracts.ensures.is_empty())
+            && (signature.ret.is_none() || !interpreter.type_hints());
+        let val = match interpreter.execute_body(body, Rc::clone(&env), tail_calls) {
+            Ok(v) => v,
+            Err(e) => match e {
+                Error::Return(v) => v,
+                re => return Err(re),
+            },
+        };
+
+        if interpreter.type_hints() {
+            check_type(paren, &signature.ret, &val, || "as return value".into())?;
+        }
+
+        if let Some((name, contracts)) = contracts {
+            let env = Rc::new(RefCell::new(Environment::from(&env)));
+            env.borrow_mut().push(val.clone());
+            for clause in &contracts.ensures {
+                let keyword = &clause.keyword;
+                check_clause(interpreter, &env, keyword, clause, "Postcondition", name)?;
+            }
+        }
+
+        Ok(val)
     }
 }
 
@@ -94,3 +226,61 @@ impl PartialEq for Function {
         false
     }
 }
+
+pub fn check_param(
+    paren: &TokenAstInfo,
+    param: &TokenAstInfo,
+    ann: &Option<TypeAnn>,
+    arg: &Value,
+) -> Result<(), Error> {
+    check_type(paren, ann, arg, || {
+        format!("for parameter '{}'", param.get_name())
+    })
+}
+
+/// Checks a value against an optional annotation, `what` describes the value
+/// in the error message
+pub fn check_type(
+    paren: &TokenAstInfo,
+    ann: &Option<TypeAnn>,
+    val: &Value,
+    what: impl FnOnce() -> String,
+) -> Result<(), Error> {
+    match ann {
+        Some(ann) if !ann.tpe.accepts(Type::of(val)) => Err(RuntimeError {
+            token: paren.clone(),
+            msg: format!(
+                "Expected {} {} but got {}",
+                ann.tpe,
+                what(),
+                val.type_name()
+            ),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn check_clause(
+    interpreter: &mut Interpreter,
+    env: &Rc<RefCell<Environment>>,
+    token: &TokenAstInfo,
+    clause: &Clause,
+    kind: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let val = interpreter.evaluate_in(&clause.cond, Rc::clone(env))?;
+    if is_truthy(&val) {
+        Ok(())
+    } else {
+        Err(contract_violation(token, kind, name, &clause.source))
+    }
+}
+
+pub fn contract_violation(token: &TokenAstInfo, kind: &str, name: &str, source: &str) -> Error {
+    RuntimeError {
+        token: token.clone(),
+        msg: format!("{kind} of '{name}' failed: {source}"),
+    }
+    .into()
+}
@@ -0,0 +1,249 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    environment::Environment,
+    function::Function,
+    value::{Record, Value},
+    vm::chunk::Closure,
+};
+
+/// Tracked objects before the first automatic collection. Later ones run
+/// once twice as many objects are tracked as survived the previous one.
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// Reported by the `gc` native
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub collections: usize,
+    /// Objects reclaimed by the last collection
+    pub freed: usize,
+    /// Objects reclaimed by all collections
+    pub total_freed: usize,
+    /// Tracked objects that survived the last collection
+    pub tracked: usize,
+}
+
+/// Reclaims reference cycles between closures and what they capture. Every
+/// such cycle goes through a scope captured by a closure or the cell of a
+/// captured VM local, so only those are tracked.
+///
+/// A collection finds everything reachable from the tracked objects and
+/// subtracts the references between them from their reference counts.
+/// Objects with references left are held from outside, by the interpreter,
+/// the VM stack or a suspended fiber. Whatever can't be reached from those is
+/// garbage, and clearing its scopes and cells breaks the cycles.
+pub struct Collector {
+    envs: Vec<Weak<RefCell<Environment>>>,
+    cells: Vec<Weak<RefCell<Value>>>,
+    threshold: usize,
+    stats: Stats,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            envs: vec![],
+            cells: vec![],
+            threshold: INITIAL_THRESHOLD,
+            stats: Stats::default(),
+        }
+    }
+}
+
+impl Collector {
+    /// Tracks a scope captured by a closure, collecting garbage when enough
+    /// objects were tracked
+    pub fn track_env(&mut self, env: &Rc<RefCell<Environment>>) {
+        // Closures created in a loop capture the same scope over and over
+        if self.envs.last().map(Weak::as_ptr) != Some(Rc::as_ptr(env)) {
+            self.envs.push(Rc::downgrade(env));
+            self.collect_if_due();
+        }
+    }
+
+    pub fn track_cell(&mut self, cell: &Rc<RefCell<Value>>) {
+        self.cells.push(Rc::downgrade(cell));
+        self.collect_if_due();
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn collect_if_due(&mut self) {
+        if self.envs.len() + self.cells.len() >= self.threshold {
+            self.collect();
+        }
+    }
+
+    pub fn collect(&mut self) -> Stats {
+        let tracked = (self.envs.iter().filter_map(Weak::upgrade).map(Node::Env))
+            .chain(self.cells.iter().filter_map(Weak::upgrade).map(Node::Cell));
+        let mut graph = Graph::default();
+        for node in tracked {
+            graph.discover(node, false);
+        }
+        while let Some(id) = graph.pending.pop() {
+            let node = graph.nodes[&id].0.clone();
+            node.children(&mut |child| graph.discover(child, true));
+        }
+
+        let mut live = HashSet::new();
+        let mut pending: Vec<_> = (graph.nodes.iter())
+            .filter(|(_, (_, external))| *external > 0)
+            .map(|(id, _)| *id)
+            .collect();
+        live.extend(pending.iter().copied());
+        while let Some(id) = pending.pop() {
+            graph.nodes[&id].0.children(&mut |child| {
+                if live.insert(child.id()) {
+                    pending.push(child.id());
+                }
+            });
+        }
+
+        let mut freed = 0;
+        for (id, (node, _)) in &graph.nodes {
+            if !live.contains(id) && node.clear() {
+                freed += 1;
+            }
+        }
+        // Cleared objects are only freed once the graph lets go of them
+        drop(graph);
+
+        let mut seen = HashSet::new();
+        self.envs
+            .retain(|env| env.strong_count() > 0 && seen.insert(env.as_ptr() as *const ()));
+        self.cells
+            .retain(|cell| cell.strong_count() > 0 && seen.insert(cell.as_ptr() as *const ()));
+
+        let tracked = self.envs.len() + self.cells.len();
+        self.threshold = INITIAL_THRESHOLD.max(2 * tracked);
+        self.stats = Stats {
+            collections: self.stats.collections + 1,
+            freed,
+            total_freed: self.stats.total_freed + freed,
+            tracked,
+        };
+
+        self.stats
+    }
+}
+
+#[derive(Default)]
+struct Graph {
+    /// Every object found, with the number of references to it from outside
+    /// the graph
+    nodes: HashMap<*const (), (Node, isize)>,
+    /// Found objects whose references weren't followed yet
+    pending: Vec<*const ()>,
+}
+
+impl Graph {
+    fn discover(&mut self, node: Node, referenced: bool) {
+        let id = node.id();
+        let edge = referenced as isize;
+        match self.nodes.get_mut(&id) {
+            Some((_, external)) => *external -= edge,
+            None => {
+                // The copy kept in the graph isn't a reference either
+                let external = node.strong_count() as isize - 1 - edge;
+                self.nodes.insert(id, (node, external));
+                self.pending.push(id);
+            }
+        }
+    }
+}
+
+/// Object that may be part of a cycle, or lead to one
+#[derive(Clone)]
+enum Node {
+    Env(Rc<RefCell<Environment>>),
+    Cell(Rc<RefCell<Value>>),
+    Closure(Rc<Closure>),
+    Tuple(Rc<[Value]>),
+    Record(Rc<Record>),
+}
+
+impl Node {
+    fn id(&self) -> *const () {
+        match self {
+            Node::Env(env) => Rc::as_ptr(env) as *const (),
+            Node::Cell(cell) => Rc::as_ptr(cell) as *const (),
+            Node::Closure(closure) => Rc::as_ptr(closure) as *const (),
+            Node::Tuple(items) => Rc::as_ptr(items) as *const (),
+            Node::Record(record) => Rc::as_ptr(record) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(env) => Rc::strong_count(env),
+            Node::Cell(cell) => Rc::strong_count(cell),
+            Node::Closure(closure) => Rc::strong_count(closure),
+            Node::Tuple(items) => Rc::strong_count(items),
+            Node::Record(record) => Rc::strong_count(record),
+        }
+    }
+
+    /// Calls `f` with every object this one references. Scopes and cells
+    /// borrowed by a suspended fiber are skipped, which keeps what they
+    /// reference alive.
+    fn children(&self, f: &mut impl FnMut(Node)) {
+        match self {
+            Node::Env(env) => {
+                let Ok(env) = env.try_borrow() else {
+                    return;
+                };
+                if let Some(enclosing) = env.enclosing() {
+                    f(Node::Env(Rc::clone(enclosing)));
+                }
+                env.values().iter().for_each(|val| references(val, f));
+            }
+            Node::Cell(cell) => {
+                if let Ok(val) = cell.try_borrow() {
+                    references(&val, f);
+                }
+            }
+            Node::Closure(closure) => {
+                for cell in &closure.upvalues {
+                    f(Node::Cell(Rc::clone(cell)));
+                }
+            }
+            Node::Tuple(items) => items.iter().for_each(|val| references(val, f)),
+            Node::Record(record) => record.values().for_each(|val| references(val, f)),
+        }
+    }
+
+    /// Drops what a garbage scope or cell references, returns `false` if it
+    /// is in use
+    fn clear(&self) -> bool {
+        match self {
+            Node::Env(env) => env.try_borrow_mut().map(|mut env| env.clear()).is_ok(),
+            Node::Cell(cell) => cell
+                .try_borrow_mut()
+                .map(|mut val| *val = Value::Nil)
+                .is_ok(),
+            // Freed along with the scopes and cells referencing them
+            Node::Closure(_) | Node::Tuple(_) | Node::Record(_) => true,
+        }
+    }
+}
+
+/// Sets can't contain functions, channels and promises keep the values they
+/// hold alive
+fn references(val: &Value, f: &mut impl FnMut(Node)) {
+    match val {
+        Value::Fun(Function::LoxFun { closure, .. } | Function::LoxLambda { closure, .. }) => {
+            f(Node::Env(Rc::clone(closure)))
+        }
+        Value::Fun(Function::Closure(closure)) => f(Node::Closure(Rc::clone(closure))),
+        Value::Tuple(items) => f(Node::Tuple(Rc::clone(items))),
+        Value::Record(record) => f(Node::Record(Rc::clone(record))),
+        _ => (),
+    }
+}
@@ -0,0 +1,49 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use crate::fiber::is_fiber_thread;
+
+/// Interned strings before the first sweep for unused ones. Later sweeps run
+/// once twice as many strings are interned as survived the previous one.
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// Only used while scanning and loading scripts, on the thread running the
+/// main script. Fiber threads must not intern: their thread locals are
+/// dropped after they hand the baton over, and the strings are shared.
+struct Interner {
+    strings: HashSet<Rc<str>>,
+    threshold: usize,
+}
+
+thread_local! {
+    static STRINGS: RefCell<Interner> = RefCell::new(Interner {
+        strings: HashSet::new(),
+        threshold: INITIAL_THRESHOLD,
+    });
+}
+
+/// Shared copy of an identifier or string literal. Equal strings interned
+/// here are the same allocation, so comparing them only compares pointers.
+pub fn intern(s: &str) -> Rc<str> {
+    debug_assert!(!is_fiber_thread(), "String interned on a fiber thread");
+    STRINGS.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(interned) = interner.strings.get(s) {
+            return Rc::clone(interned);
+        }
+
+        if interner.strings.len() >= interner.threshold {
+            // Strings nothing else holds anymore, like literals of REPL lines
+            interner.strings.retain(|s| Rc::strong_count(s) > 1);
+            interner.threshold = INITIAL_THRESHOLD.max(2 * interner.strings.len());
+        }
+
+        let interned: Rc<str> = s.into();
+        interner.strings.insert(Rc::clone(&interned));
+        interned
+    })
+}
+
+/// Strings interned on this thread, including unused ones not swept yet
+pub fn interned() -> usize {
+    STRINGS.with(|interner| interner.borrow().strings.len())
+}
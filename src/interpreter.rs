@@ -1,26 +1,80 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
 
 use crate::{
     ast::{stmt::Stmt, visitor::Visitor, Expr, TokenAstInfo},
-    environment::Environment,
-    error::{Error, RuntimeError},
-    function::Function,
+    environment::{Environment, Local},
+    error::{Error, Limit, RuntimeError},
+    event_loop::{self, EventLoop},
+    fiber::{self, Channel, Scheduler},
+    function::{Function, TailCall},
+    gc::Collector,
     lox_callable::Callable,
+    memory::{Meter, Metering, Usage},
     tokentype::TokenType,
-    value::Value,
+    value::{Record, Set, Value},
+    vm::{self, chunk::Proto, compiler::Compiler},
 };
 
-#[derive(Default)]
+/// Steps between two looks at the clock when a deadline is set
+const DEADLINE_CHECK_INTERVAL: u64 = 4096;
+
+/// Nested calls allowed by default. Each one takes Rust stack, several times
+/// more in debug builds, and this many fit the stack of a fiber.
+pub const MAX_CALL_DEPTH: usize = if cfg!(debug_assertions) { 250 } else { 1000 };
+
 pub struct Interpreter {
     pub globals: Rc<RefCell<Environment>>,
     environment: Rc<RefCell<Environment>>,
-    locals: HashMap<TokenAstInfo, usize>,
+    /// Expressions registered with `defer`, one frame per running block
+    deferred: Vec<Vec<Expr>>,
+    /// `defer` frames below the body of the running function, `None` when
+    /// its calls in `return` can't be tail calls
+    tail_call_base: Option<usize>,
+    /// When disabled `assert` statements and function contracts are skipped
+    /// without evaluation
+    assertions: bool,
+    /// When enabled annotated parameters and return values are checked on
+    /// every call
+    type_hints: bool,
+    /// When enabled scripts are compiled and run by the bytecode VM
+    bytecode: bool,
+    scheduler: Arc<Scheduler>,
+    /// Id of the fiber this interpreter runs, `0` for the main script
+    fiber: usize,
+    /// Runtime errors of spawned fibers, reported after the script finishes
+    fiber_errors: Rc<RefCell<Vec<Error>>>,
+    event_loop: Rc<RefCell<EventLoop>>,
+    /// Shared by all fibers, like the globals
+    collector: Rc<RefCell<Collector>>,
+    /// Calls of this fiber that haven't returned yet. Tail calls replace the
+    /// call they return from.
+    call_depth: usize,
+    max_call_depth: usize,
+    /// Steps left to all fibers, unbounded when `None`
+    step_budget: Rc<Cell<Option<u64>>>,
+    /// Steps since the clock was last checked against the deadline
+    steps: u64,
+    deadline: Option<Instant>,
+    /// Shared by all fibers, like the step budget
+    meter: Arc<Meter>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Visitor for Interpreter {
     type Output = Result<Value, Error>;
 
     fn visit_statement(&mut self, stmt: &Stmt) -> Self::Output {
+        self.step()?;
         match stmt {
             Stmt::Expr(expr) => {
                 self.visit_expression(expr)?;
@@ -32,9 +86,11 @@ impl Visitor for Interpreter {
                 }
                 println!("{res}")
             }
-            Stmt::Var { name, initializer } => {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
                 let val = self.visit_expression(initializer)?;
-                self.environment.borrow_mut().define(name, val)?;
+                self.environment.borrow_mut().define(name, val);
             }
             Stmt::Block(statements) => {
                 let env = Rc::new(RefCell::new(Environment::from(&self.environment)));
@@ -52,34 +108,98 @@ impl Visitor for Interpreter {
                     self.visit_statement(else_stmt.as_ref().unwrap())?;
                 }
             }
-            Stmt::While { cond, body } => loop {
+            Stmt::While {
+                cond,
+                body,
+                inc,
+                label,
+            } => loop {
+                let cond = self.visit_expression(cond)?;
+                if !is_truthy(&cond) || !self.loop_body(body, label)? {
+                    break;
+                }
+                if let Some(inc) = inc {
+                    self.visit_expression(inc)?;
+                }
+            },
+            Stmt::DoWhile { body, cond, label } => loop {
+                if !self.loop_body(body, label)? {
+                    break;
+                }
                 let cond = self.visit_expression(cond)?;
                 if !is_truthy(&cond) {
                     break;
                 }
-                self.visit_statement(body)?;
             },
-            Stmt::Function { name, params, body } => {
+            Stmt::Break { label, .. } => {
+                return Err(Error::Break(label.as_ref().map(|l| l.get_name().into())));
+            }
+            Stmt::Continue { label, .. } => {
+                return Err(Error::Continue(label.as_ref().map(|l| l.get_name().into())));
+            }
+            Stmt::Function {
+                name,
+                params,
+                signature,
+                body,
+                is_async,
+                decorators,
+                contracts,
+            } => {
+                let mut applied = vec![];
+                for (at, decorator) in decorators {
+                    applied.push((at, self.visit_expression(decorator)?));
+                }
+
+                self.collector.borrow_mut().track_env(&self.environment);
                 let fun = Function::LoxFun {
                     name: name.clone(),
-                    params: params.clone(),
-                    body: body.clone(),
+                    params: params.as_slice().into(),
+                    signature: Rc::new(signature.clone()),
+                    body: body.as_slice().into(),
                     closure: Rc::clone(&self.environment),
+                    is_async: *is_async,
+                    contracts: Rc::new(contracts.clone()),
                 };
-                let fun = Value::Fun(fun);
-                self.environment.borrow_mut().define(name, fun)?;
+                let mut fun = Value::Fun(fun);
+                // The decorator closest to the function is applied first
+                for (at, decorator) in applied.into_iter().rev() {
+                    fun = self.decorate(at, decorator, fun)?;
+                }
+                self.environment.borrow_mut().define(name, fun);
             }
             Stmt::Return { val, .. } => {
-                let val = self.visit_expression(val)?;
+                let val = if self.in_tail_position() {
+                    self.tail_expression(val)?
+                } else {
+                    self.visit_expression(val)?
+                };
 
                 return Err(Error::Return(val));
             }
+            Stmt::Assert {
+                keyword,
+                cond,
+                msg,
+                source,
+            } => {
+                if self.assertions {
+                    self.assert(keyword, cond, msg, source)?;
+                }
+            }
+            Stmt::Defer(expr) => match self.deferred.last_mut() {
+                Some(frame) => frame.push(expr.clone()),
+                None => unreachable!(
+                    "Well, that shouldn't happen... ICE Code: 0x6: Deferred outside of any block"
+                ),
+            },
         };
 
         Ok(Value::Nil)
     }
 
     fn visit_expression(&mut self, expr: &crate::ast::Expr) -> Self::Output {
+        self.step()?;
         match expr {
             Expr::Literal(val) => Ok(val.clone()),
             Expr::Grouping(expr) => self.visit_expression(expr),
@@ -96,90 +216,82 @@ impl Visitor for Interpreter {
             Expr::Binary { left, op, right } => {
                 let left = self.visit_expression(left)?;
                 let right = self.visit_expression(right)?;
-                let op = op;
-
-                match op.kind {
-                    TokenType::Minus => self.minus(left, right, op),
-                    TokenType::Slash => self.slash(left, right, op),
-                    TokenType::Star => self.star(left, right, op),
-                    TokenType::Plus => self.plus(left, right, op),
-                    TokenType::Greater => self.greater(left, right, op),
-                    TokenType::GreaterEq => self.greater_eq(left, right, op),
-                    TokenType::Less => self.less(left, right, op),
-                    TokenType::LessEq => self.less_eq(left, right, op),
-                    TokenType::BangEq => Ok(Value::Bool(!left.eq(&right))),
-                    TokenType::EqEq => Ok(Value::Bool(left.eq(&right))),
-                    TokenType::Percent => self.percent(left, right, op),
-                    _ => unreachable!(),
-                }
-                .map_err(|re| re.into())
+                self.binary(left, op, right)
             }
-            Expr::Variable(name) => self.lookup_variable(name),
-            Expr::Assign { name, val } => {
+            Expr::Variable { name, local } => self.lookup_variable(name, local.get()),
+            Expr::Assign { name, val, local } => {
                 let val = self.visit_expression(val)?;
 
-                let distance = self.locals.get(name);
-                if let Some(distance) = distance {
-                    self.environment
-                        .borrow_mut()
-                        .assign_at(*distance, name, &val)?;
+                if let Some(local) = local.get() {
+                    self.environment.borrow_mut().assign_at(local, &val);
                 } else {
                     self.globals.borrow_mut().assign(name, &val)?;
                 }
                 Ok(val)
             }
-            Expr::Logical { left, op, right } => {
-                let left = self.visit_expression(left)?;
-
-                if op.kind == TokenType::Or {
-                    if is_truthy(&left) {
-                        return Ok(left);
-                    }
-                } else if !is_truthy(&left) {
-                    return Ok(left);
-                }
-
-                Ok(self.visit_expression(right)?)
-            }
+            Expr::Logical { left, op, right } => match self.short_circuit(left, op)? {
+                Some(left) => Ok(left),
+                None => self.visit_expression(right),
+            },
             Expr::Call {
                 callee,
                 paren,
                 args,
             } => {
-                let callee = self.visit_expression(callee)?;
-
-                let mut arguments = vec![];
-                for arg in args {
-                    arguments.push(self.visit_expression(arg)?);
+                let (fun, args) = self.callee_and_args(callee, paren, args)?;
+                self.enter_call(paren)?;
+                let res = fun.call(self, paren, &args);
+                self.exit_call();
+                res
+            }
+            Expr::Tuple(items) => {
+                let mut values = vec![];
+                for item in items {
+                    values.push(self.visit_expression(item)?);
                 }
-
-                if let Value::Fun(fun) = callee {
-                    if arguments.len() != fun.arity() {
-                        Err(RuntimeError {
-                            token: paren.clone(),
-                            msg: format!(
-                                "Expected {} arguments but got {}",
-                                fun.arity(),
-                                arguments.len()
-                            ),
-                        }
-                        .into())
-                    } else {
-                        fun.call(self, &arguments)
-                    }
-                } else {
-                    Err(RuntimeError {
-                        token: paren.clone(),
-                        msg: "Can only call functions and classes".into(),
-                    }
-                    .into())
+                Ok(Value::Tuple(values.into()))
+            }
+            Expr::Set { brace, items } => {
+                let mut set = Set::default();
+                for item in items {
+                    let item = self.visit_expression(item)?;
+                    check_set_element(brace, &item)?;
+                    set.insert(item);
+                }
+                Ok(Value::Set(Rc::new(set)))
+            }
+            Expr::Record(fields) => {
+                let mut values = vec![];
+                for (name, val) in fields {
+                    values.push((Rc::clone(name.interned_name()), self.visit_expression(val)?));
                 }
+                Ok(Value::Record(Rc::new(Record::new(values))))
             }
-            Expr::Lambda { body, params } => {
+            Expr::Get { object, name } => {
+                let object = self.visit_expression(object)?;
+                get_field(&object, name)
+            }
+            Expr::Await { keyword, expr } => {
+                let mut val = self.visit_expression(expr)?;
+                // A promise resolved with another promise waits for that one too
+                while let Value::Promise(promise) = val {
+                    val = fiber::await_promise(self, keyword, &promise)?;
+                }
+                Ok(val)
+            }
+            Expr::Lambda {
+                params,
+                signature,
+                body,
+                is_async,
+            } => {
+                self.collector.borrow_mut().track_env(&self.environment);
                 let lambda = Function::LoxLambda {
-                    body: body.clone(),
-                    params: params.clone(),
+                    body: body.as_slice().into(),
+                    params: params.as_slice().into(),
+                    signature: Rc::new(signature.clone()),
                     closure: Rc::clone(&self.environment),
+                    is_async: *is_async,
                 };
                 Ok(Value::Fun(lambda))
             }
@@ -189,14 +301,14 @@ impl Visitor for Interpreter {
 
 impl Interpreter {
     pub fn new() -> Self {
-        let clock = |_: &[Value]| -> Result<Value, Error> {
+        let clock = |_: &mut Interpreter, _: &TokenAstInfo, _: &[Value]| -> Result<Value, Error> {
             match std::time::UNIX_EPOCH.elapsed() {
                 Ok(d) => Ok(Value::Number(d.as_secs_f64())),
                 Err(e) => panic!("{e}"),
             }
         };
 
-        let num = |val: &[Value]| -> Result<Value, Error> {
+        let num = |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
             match &val[0] {
                 Value::Number(n) => Ok(Value::Number(*n)),
                 Value::Bool(b) => Ok(if *b {
@@ -216,22 +328,152 @@ impl Interpreter {
             }
         };
 
-        let tpe = |val: &[Value]| -> Result<Value, Error> {
-            let tpe = match &val[0] {
-                Value::Nil => "nil".to_string(),
-                Value::Bool(_) => "bool".to_string(),
-                Value::Number(_) => "number".to_string(),
-                Value::String(_) => "string".to_string(),
-                Value::Fun(_) => format!("{}", &val[0]),
+        let tpe = |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+            Ok(Value::String(val[0].type_name().into()))
+        };
+
+        let string =
+            |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+                Ok(Value::String(val[0].to_string().into()))
             };
 
-            Ok(Value::String(tpe))
+        let bool = |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+            Ok(Value::Bool(is_truthy(&val[0])))
         };
 
-        let string =
-            |val: &[Value]| -> Result<Value, Error> { Ok(Value::String(val[0].to_string())) };
+        let spawn = |interpreter: &mut Interpreter,
+                     paren: &TokenAstInfo,
+                     val: &[Value]|
+         -> Result<Value, Error> {
+            match &val[0] {
+                Value::Fun(fun) if fun.arity() == 0 => {
+                    let (fun, at) = (fun.clone(), paren.clone());
+                    fiber::spawn(interpreter, paren, move |interpreter, started| {
+                        if let Err(e) = started.and_then(|()| fun.call(interpreter, &at, &[])) {
+                            interpreter.fiber_error(e);
+                        }
+                    });
+                    Ok(Value::Nil)
+                }
+                _ => Err(Error::NativeCallError(
+                    "spawn expects a function without parameters".into(),
+                )),
+            }
+        };
+
+        let yield_now = |interpreter: &mut Interpreter,
+                         _: &TokenAstInfo,
+                         _: &[Value]|
+         -> Result<Value, Error> {
+            interpreter.scheduler.yield_now(interpreter.fiber);
+            Ok(Value::Nil)
+        };
 
-        let bool = |val: &[Value]| -> Result<Value, Error> { Ok(Value::Bool(is_truthy(&val[0]))) };
+        let channel =
+            |_: &mut Interpreter, _: &TokenAstInfo, _: &[Value]| -> Result<Value, Error> {
+                Ok(Value::Channel(Rc::new(Channel::default())))
+            };
+
+        let send = |interpreter: &mut Interpreter,
+                    _: &TokenAstInfo,
+                    val: &[Value]|
+         -> Result<Value, Error> {
+            match &val[0] {
+                Value::Channel(channel) => {
+                    fiber::send(interpreter, channel, val[1].clone());
+                    Ok(Value::Nil)
+                }
+                _ => Err(Error::NativeCallError("send expects a channel".into())),
+            }
+        };
+
+        let receive = |interpreter: &mut Interpreter,
+                       paren: &TokenAstInfo,
+                       val: &[Value]|
+         -> Result<Value, Error> {
+            match &val[0] {
+                Value::Channel(channel) => fiber::receive(interpreter, paren, channel),
+                _ => Err(Error::NativeCallError("receive expects a channel".into())),
+            }
+        };
+
+        let set_timeout = |interpreter: &mut Interpreter,
+                           paren: &TokenAstInfo,
+                           val: &[Value]|
+         -> Result<Value, Error> {
+            match val {
+                [Value::Fun(fun), Value::Number(ms)] if fun.arity() == 0 && *ms >= 0. => {
+                    event_loop::set_timeout(interpreter, fun.clone(), paren.clone(), *ms)?;
+                    Ok(Value::Nil)
+                }
+                _ => Err(Error::NativeCallError(
+                    "set_timeout expects a function without parameters and a delay in ms".into(),
+                )),
+            }
+        };
+
+        let sleep = |interpreter: &mut Interpreter,
+                     _: &TokenAstInfo,
+                     val: &[Value]|
+         -> Result<Value, Error> {
+            match &val[0] {
+                Value::Number(ms) if *ms >= 0. => {
+                    Ok(Value::Promise(event_loop::sleep(interpreter, *ms)?))
+                }
+                _ => Err(Error::NativeCallError(
+                    "sleep expects a non-negative number of ms".into(),
+                )),
+            }
+        };
+
+        let gc = |interpreter: &mut Interpreter,
+                  _: &TokenAstInfo,
+                  _: &[Value]|
+         -> Result<Value, Error> {
+            let stats = interpreter.collector.borrow_mut().collect();
+            let fields = [
+                ("collections", stats.collections),
+                ("freed", stats.freed),
+                ("total_freed", stats.total_freed),
+                ("tracked", stats.tracked),
+            ];
+            let fields = fields
+                .into_iter()
+                .map(|(name, n)| (Rc::from(name), Value::Number(n as f64)))
+                .collect();
+            Ok(Value::Record(Rc::new(Record::new(fields))))
+        };
+
+        let memory = |interpreter: &mut Interpreter,
+                      _: &TokenAstInfo,
+                      _: &[Value]|
+         -> Result<Value, Error> {
+            let usage = interpreter.memory();
+            let fields = [("current", usage.current), ("peak", usage.peak)];
+            let fields = fields
+                .into_iter()
+                .map(|(name, n)| (Rc::from(name), Value::Number(n as f64)))
+                .collect();
+            Ok(Value::Record(Rc::new(Record::new(fields))))
+        };
+
+        let union =
+            |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+                let (l, r) = set_operands("union", val)?;
+                Ok(Value::Set(Rc::new(l.union(r))))
+            };
+
+        let intersection =
+            |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+                let (l, r) = set_operands("intersection", val)?;
+                Ok(Value::Set(Rc::new(l.intersection(r))))
+            };
+
+        let difference =
+            |_: &mut Interpreter, _: &TokenAstInfo, val: &[Value]| -> Result<Value, Error> {
+                let (l, r) = set_operands("difference", val)?;
+                Ok(Value::Set(Rc::new(l.difference(r))))
+            };
 
         let clock = Value::Fun(Function::Native {
             arity: 0,
@@ -258,41 +500,422 @@ impl Interpreter {
             body: Box::new(tpe),
         });
 
+        let union = Value::Fun(Function::Native {
+            arity: 2,
+            body: Box::new(union),
+        });
+
+        let intersection = Value::Fun(Function::Native {
+            arity: 2,
+            body: Box::new(intersection),
+        });
+
+        let difference = Value::Fun(Function::Native {
+            arity: 2,
+            body: Box::new(difference),
+        });
+
+        let spawn = Value::Fun(Function::Native {
+            arity: 1,
+            body: Box::new(spawn),
+        });
+
+        let yield_now = Value::Fun(Function::Native {
+            arity: 0,
+            body: Box::new(yield_now),
+        });
+
+        let channel = Value::Fun(Function::Native {
+            arity: 0,
+            body: Box::new(channel),
+        });
+
+        let send = Value::Fun(Function::Native {
+            arity: 2,
+            body: Box::new(send),
+        });
+
+        let receive = Value::Fun(Function::Native {
+            arity: 1,
+            body: Box::new(receive),
+        });
+
+        let set_timeout = Value::Fun(Function::Native {
+            arity: 2,
+            body: Box::new(set_timeout),
+        });
+
+        let gc = Value::Fun(Function::Native {
+            arity: 0,
+            body: Box::new(gc),
+        });
+
+        let memory = Value::Fun(Function::Native {
+            arity: 0,
+            body: Box::new(memory),
+        });
+
+        let sleep = Value::Fun(Function::Native {
+            arity: 1,
+            body: Box::new(sleep),
+        });
+
         let globals = Rc::new(RefCell::new(Environment::new()));
         globals.borrow_mut().define_native("clock", clock);
         globals.borrow_mut().define_native("num", num);
         globals.borrow_mut().define_native("str", string);
         globals.borrow_mut().define_native("bool", bool);
         globals.borrow_mut().define_native("type", tpe);
+        globals.borrow_mut().define_native("union", union);
+        globals
+            .borrow_mut()
+            .define_native("intersection", intersection);
+        globals.borrow_mut().define_native("difference", difference);
+        globals.borrow_mut().define_native("spawn", spawn);
+        globals.borrow_mut().define_native("yield_now", yield_now);
+        globals.borrow_mut().define_native("channel", channel);
+        globals.borrow_mut().define_native("send", send);
+        globals.borrow_mut().define_native("receive", receive);
+        globals
+            .borrow_mut()
+            .define_native("set_timeout", set_timeout);
+        globals.borrow_mut().define_native("sleep", sleep);
+        globals.borrow_mut().define_native("gc", gc);
+        globals.borrow_mut().define_native("memory", memory);
 
         let environment = Rc::clone(&globals);
         Self {
             globals,
             environment,
-            locals: HashMap::new(),
+            deferred: vec![],
+            tail_call_base: None,
+            assertions: true,
+            type_hints: false,
+            bytecode: false,
+            scheduler: Arc::new(Scheduler::default()),
+            fiber: 0,
+            fiber_errors: Rc::new(RefCell::new(vec![])),
+            event_loop: Rc::new(RefCell::new(EventLoop::default())),
+            collector: Rc::new(RefCell::new(Collector::default())),
+            call_depth: 0,
+            max_call_depth: MAX_CALL_DEPTH,
+            step_budget: Rc::new(Cell::new(None)),
+            steps: 0,
+            deadline: None,
+            meter: Arc::new(Meter::default()),
+        }
+    }
+
+    /// Interpreter for a new fiber, sharing globals with this one
+    pub fn fork(&self, fiber: usize) -> Self {
+        Self {
+            globals: Rc::clone(&self.globals),
+            environment: Rc::clone(&self.globals),
+            deferred: vec![],
+            tail_call_base: None,
+            assertions: self.assertions,
+            type_hints: self.type_hints,
+            bytecode: self.bytecode,
+            scheduler: Arc::clone(&self.scheduler),
+            fiber,
+            fiber_errors: Rc::clone(&self.fiber_errors),
+            event_loop: Rc::clone(&self.event_loop),
+            collector: Rc::clone(&self.collector),
+            // Fibers run on their own stack
+            call_depth: 0,
+            max_call_depth: self.max_call_depth,
+            step_budget: Rc::clone(&self.step_budget),
+            steps: 0,
+            deadline: self.deadline,
+            meter: Arc::clone(&self.meter),
+        }
+    }
+
+    pub fn collector(&self) -> &Rc<RefCell<Collector>> {
+        &self.collector
+    }
+
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
+    pub fn event_loop(&self) -> &Rc<RefCell<EventLoop>> {
+        &self.event_loop
+    }
+
+    pub fn fiber(&self) -> usize {
+        self.fiber
+    }
+
+    pub fn fiber_error(&self, err: Error) {
+        self.fiber_errors.borrow_mut().push(err);
+    }
+
+    /// Errors of spawned fibers and unhandled promise rejections since the
+    /// last call
+    pub fn take_fiber_errors(&mut self) -> Vec<Error> {
+        self.fiber_errors.take()
+    }
+
+    pub fn set_assertions(&mut self, enabled: bool) {
+        self.assertions = enabled;
+    }
+
+    pub fn assertions(&self) -> bool {
+        self.assertions
+    }
+
+    pub fn set_type_hints(&mut self, enabled: bool) {
+        self.type_hints = enabled;
+    }
+
+    pub fn type_hints(&self) -> bool {
+        self.type_hints
+    }
+
+    /// Calls nested deeper fail with a "Stack overflow" error. The default
+    /// fits the stack of a fiber, hosts running scripts on a smaller one
+    /// should lower it.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
+    }
+
+    /// Fails scripts with `Limit::Steps` once they evaluated this many
+    /// statements and expressions, or ran this many VM instructions. Shared
+    /// by all fibers.
+    pub fn set_step_budget(&mut self, steps: Option<u64>) {
+        self.step_budget.set(steps);
+    }
+
+    /// Steps left, `None` when unbounded
+    pub fn step_budget(&self) -> Option<u64> {
+        self.step_budget.get()
+    }
+
+    /// Fails scripts with `Limit::Deadline` once it passed. Fibers spawned
+    /// afterwards share it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Fails scripts with `Limit::Memory` once they use more heap memory than
+    /// this, in bytes. Shared by all fibers.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.meter.set_limit(limit);
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.meter.limit()
+    }
+
+    /// Heap memory used by scripts so far
+    pub fn memory(&self) -> Usage {
+        self.meter.usage()
+    }
+
+    pub fn meter(&self) -> &Arc<Meter> {
+        &self.meter
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Counts a step against the budget, the deadline and the memory limit
+    pub fn step(&mut self) -> Result<(), Error> {
+        if let Some(steps) = self.step_budget.get() {
+            if steps == 0 {
+                return Err(Error::LimitExceeded(Limit::Steps));
+            }
+            self.step_budget.set(Some(steps - 1));
         }
+        if let Some(deadline) = self.deadline {
+            self.steps += 1;
+            if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(Error::LimitExceeded(Limit::Deadline));
+            }
+        }
+        if self.meter.exceeded() {
+            return Err(Error::LimitExceeded(Limit::Memory));
+        }
+        Ok(())
+    }
+
+    /// Counts a call, failing at `paren` if it nests too deep
+    pub fn enter_call(&mut self, paren: &TokenAstInfo) -> Result<(), Error> {
+        if self.call_depth >= self.max_call_depth {
+            return Err(RuntimeError {
+                token: paren.clone(),
+                msg: "Stack overflow".into(),
+            }
+            .into());
+        }
+        self.call_depth += 1;
+        Ok(())
     }
 
-    pub fn resolve(&mut self, name: &TokenAstInfo, depth: usize) {
-        self.locals.insert(name.clone(), depth);
+    pub fn exit_call(&mut self) {
+        self.call_depth -= 1;
     }
 
-    fn lookup_variable(&self, name: &TokenAstInfo) -> Result<Value, Error> {
-        let distance = self.locals.get(name);
+    pub fn set_bytecode(&mut self, enabled: bool) {
+        self.bytecode = enabled;
+    }
 
-        if let Some(distance) = distance {
-            Ok(self.environment.borrow().get_at(*distance, name.get_name()))
+    pub fn bytecode(&self) -> bool {
+        self.bytecode
+    }
+
+    fn lookup_variable(&self, name: &TokenAstInfo, local: Option<Local>) -> Result<Value, Error> {
+        if let Some(local) = local {
+            Ok(self.environment.borrow().get_at(local))
         } else {
             Ok(self.globals.borrow().get(name)?)
         }
     }
 
     pub fn interpret(&mut self, stmt: Vec<Stmt>) -> Result<(), Error> {
-        for stmt in stmt {
-            self.visit_statement(&stmt)?;
+        let _metering = Metering::start(&self.meter);
+        if self.bytecode {
+            return self.run_compiled(Compiler::new().compile(&stmt));
         }
 
-        Ok(())
+        self.deferred.push(vec![]);
+        let mut steps = || -> Result<Value, Error> {
+            for stmt in &stmt {
+                self.visit_statement(stmt)?;
+            }
+
+            Ok(Value::Nil)
+        };
+        let res = steps();
+        let res = self.run_deferred(res);
+        let event_loop = fiber::run_event_loop(self);
+        res?;
+
+        event_loop
+    }
+
+    /// Runs a script compiled for the VM, regardless of `bytecode`
+    pub fn run_compiled(&mut self, script: Proto) -> Result<(), Error> {
+        let _metering = Metering::start(&self.meter);
+        let res = vm::run_script(self, script);
+        let event_loop = fiber::run_event_loop(self);
+        res?;
+
+        event_loop
+    }
+
+    /// Runs the body of a function. Unless its result is checked afterwards,
+    /// calls in its `return` statements are left to the caller.
+    pub fn execute_body(
+        &mut self,
+        body: &[Stmt],
+        env: Rc<RefCell<Environment>>,
+        tail_calls: bool,
+    ) -> Result<Value, Error> {
+        let base = tail_calls.then_some(self.deferred.len());
+        let prev = std::mem::replace(&mut self.tail_call_base, base);
+        let res = self.execute_block(body, env);
+        self.tail_call_base = prev;
+
+        res
+    }
+
+    /// Evaluates the value of a `return`. A call it ends with is made by the
+    /// caller, so it is returned as `Error::TailCall`.
+    fn tail_expression(&mut self, expr: &Expr) -> Result<Value, Error> {
+        match expr {
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                self.step()?;
+                let (fun, args) = self.callee_and_args(callee, paren, args)?;
+                let paren = paren.clone();
+                Err(Error::TailCall(Box::new(TailCall { fun, paren, args })))
+            }
+            Expr::Grouping(expr) => {
+                self.step()?;
+                self.tail_expression(expr)
+            }
+            Expr::Logical { left, op, right } => {
+                self.step()?;
+                match self.short_circuit(left, op)? {
+                    Some(left) => Ok(left),
+                    None => self.tail_expression(right),
+                }
+            }
+            expr => self.visit_expression(expr),
+        }
+    }
+
+    /// The left operand if it decides the result of `and` or `or`
+    fn short_circuit(&mut self, left: &Expr, op: &TokenAstInfo) -> Result<Option<Value>, Error> {
+        let left = self.visit_expression(left)?;
+        let decided = if op.kind == TokenType::Or {
+            is_truthy(&left)
+        } else {
+            !is_truthy(&left)
+        };
+
+        Ok(decided.then_some(left))
+    }
+
+    /// Expressions deferred in the function must run after the call
+    fn in_tail_position(&self) -> bool {
+        self.tail_call_base
+            .is_some_and(|base| self.deferred[base..].iter().all(Vec::is_empty))
+    }
+
+    fn callee_and_args(
+        &mut self,
+        callee: &Expr,
+        paren: &TokenAstInfo,
+        args: &[Expr],
+    ) -> Result<(Function, Vec<Value>), Error> {
+        let callee = self.visit_expression(callee)?;
+
+        let mut arguments = vec![];
+        for arg in args {
+            arguments.push(self.visit_expression(arg)?);
+        }
+
+        match callee {
+            Value::Fun(fun) if arguments.len() != fun.arity() => Err(RuntimeError {
+                token: paren.clone(),
+                msg: format!(
+                    "Expected {} arguments but got {}",
+                    fun.arity(),
+                    arguments.len()
+                ),
+            }
+            .into()),
+            Value::Fun(fun) => Ok((fun, arguments)),
+            _ => Err(RuntimeError {
+                token: paren.clone(),
+                msg: "Can only call functions and classes".into(),
+            }
+            .into()),
+        }
+    }
+
+    /// Evaluates the expression in `env` instead of the current environment
+    pub fn evaluate_in(
+        &mut self,
+        expr: &Expr,
+        env: Rc<RefCell<Environment>>,
+    ) -> Result<Value, Error> {
+        let prev = std::mem::replace(&mut self.environment, env);
+        let res = self.visit_expression(expr);
+        self.environment = prev;
+
+        res
     }
 
     pub fn execute_block(
@@ -301,6 +924,7 @@ impl Interpreter {
         env: Rc<RefCell<Environment>>,
     ) -> Result<Value, Error> {
         let prev = Rc::clone(&self.environment);
+        self.deferred.push(vec![]);
         let steps = || -> Result<Value, Error> {
             self.environment = env;
             for stmt in statements {
@@ -310,14 +934,152 @@ impl Interpreter {
             Ok(Value::Nil)
         };
         let res = steps();
+        let res = self.run_deferred(res);
         self.environment = prev;
         res
     }
 
+    /// Evaluates an assertion. Operands of a failed comparison are included in
+    /// the error message, so they are evaluated separately here.
+    fn assert(
+        &mut self,
+        keyword: &TokenAstInfo,
+        cond: &Expr,
+        msg: &Option<Expr>,
+        source: &str,
+    ) -> Result<(), Error> {
+        let mut operands = None;
+        let res = match ungrouped(cond) {
+            Expr::Binary { left, op, right } if is_comparison(op.kind) => {
+                let left = self.visit_expression(left)?;
+                let right = self.visit_expression(right)?;
+                let res = self.binary(left.clone(), op, right.clone())?;
+                operands = Some((left, right));
+                res
+            }
+            cond => self.visit_expression(cond)?,
+        };
+
+        if is_truthy(&res) {
+            return Ok(());
+        }
+
+        let msg = match msg {
+            Some(msg) => Some(self.visit_expression(msg)?),
+            None => None,
+        };
+
+        Err(assert_failure(keyword, source, msg, operands))
+    }
+
+    /// Runs one iteration of a loop body. Returns `false` if the loop was left
+    /// with `break`, loop exits aimed at an enclosing loop are propagated.
+    fn loop_body(&mut self, body: &Stmt, label: &Option<TokenAstInfo>) -> Result<bool, Error> {
+        let targets_this = |target: &Option<String>| match (target, label) {
+            (None, _) => true,
+            (Some(target), Some(label)) => target == label.get_name(),
+            (Some(_), None) => false,
+        };
+
+        match self.visit_statement(body) {
+            Ok(_) => Ok(true),
+            Err(Error::Break(target)) if targets_this(&target) => Ok(false),
+            Err(Error::Continue(target)) if targets_this(&target) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Pops the innermost `defer` frame and runs it in LIFO order in the
+    /// environment of the exiting block. Errors raised by deferred expressions
+    /// replace a normal exit, `return`, `break` or `continue`, but not an
+    /// error that is already unwinding the block.
+    fn run_deferred(&mut self, mut res: Result<Value, Error>) -> Result<Value, Error> {
+        let frame = self.deferred.pop().unwrap_or_default();
+        for expr in frame.iter().rev() {
+            if let Err(e) = self.visit_expression(expr) {
+                if matches!(
+                    res,
+                    Ok(_) | Err(Error::Return(_) | Error::Break(_) | Error::Continue(_))
+                ) {
+                    res = Err(e);
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Natives report errors without a location, those of a decorator are
+    /// reported at its '@'. Errors raised in the body of a Lox decorator keep
+    /// their location and name the decorator's line.
+    pub fn decorate(
+        &mut self,
+        at: &TokenAstInfo,
+        decorator: Value,
+        fun: Value,
+    ) -> Result<Value, Error> {
+        match decorator {
+            Value::Fun(decorator) if decorator.arity() == 1 => {
+                decorator.call(self, at, &[fun]).map_err(|e| match e {
+                    Error::NativeCallError(msg) => RuntimeError {
+                        token: at.clone(),
+                        msg,
+                    }
+                    .into(),
+                    Error::RuntimeError(RuntimeError { token, msg }) if token != *at => {
+                        RuntimeError {
+                            msg: format!("{msg} in decorator at line {}", at.line),
+                            token,
+                        }
+                        .into()
+                    }
+                    e => e,
+                })
+            }
+            _ => Err(RuntimeError {
+                token: at.clone(),
+                msg: "Decorator must be a function taking one argument".into(),
+            }
+            .into()),
+        }
+    }
+
+    pub fn binary(&self, left: Value, op: &TokenAstInfo, right: Value) -> Result<Value, Error> {
+        match op.kind {
+            TokenType::Minus => self.minus(left, right, op),
+            TokenType::Slash => self.slash(left, right, op),
+            TokenType::Star => self.star(left, right, op),
+            TokenType::Plus => self.plus(left, right, op),
+            TokenType::Greater => self.greater(left, right, op),
+            TokenType::GreaterEq => self.greater_eq(left, right, op),
+            TokenType::Less => self.less(left, right, op),
+            TokenType::LessEq => self.less_eq(left, right, op),
+            TokenType::BangEq => Ok(Value::Bool(!left.eq(&right))),
+            TokenType::EqEq => Ok(Value::Bool(left.eq(&right))),
+            TokenType::Percent => self.percent(left, right, op),
+            TokenType::In => self.contains(left, right, op),
+            _ => unreachable!(),
+        }
+        .map_err(|re| re.into())
+    }
+
+    fn contains(&self, l: Value, r: Value, op: &TokenAstInfo) -> Result<Value, RuntimeError> {
+        match (l, r) {
+            (l, Value::Set(set)) => Ok(Value::Bool(set.contains(&l))),
+            (l, Value::Tuple(items)) => Ok(Value::Bool(items.contains(&l))),
+            (Value::String(l), Value::String(r)) => Ok(Value::Bool(r.contains(&*l))),
+            (Value::String(l), Value::Record(r)) => Ok(Value::Bool(r.get(&l).is_some())),
+            _ => Err(RuntimeError {
+                token: op.clone(),
+                msg: "Right operand of 'in' must be a set, a tuple, a string or a record".into(),
+            }),
+        }
+    }
+
     fn plus(&self, l: Value, r: Value, op: &TokenAstInfo) -> Result<Value, RuntimeError> {
         match (l, r) {
             (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
-            (Value::String(l), Value::String(r)) => Ok(Value::String(l + &r)),
+            (Value::String(l), Value::String(r)) => Ok(Value::String([&*l, &*r].concat().into())),
             _ => Err(RuntimeError {
                 token: op.clone(),
                 msg: "Operands must be either two strings or two numbers".to_string(),
@@ -382,7 +1144,97 @@ impl Interpreter {
     }
 }
 
-fn is_truthy(val: &Value) -> bool {
+fn set_operands<'a>(name: &str, val: &'a [Value]) -> Result<(&'a Set, &'a Set), Error> {
+    match val {
+        [Value::Set(l), Value::Set(r)] => Ok((l, r)),
+        _ => Err(Error::NativeCallError(format!("{name} expects two sets"))),
+    }
+}
+
+/// Expression inside any parentheses, so `assert (a == b);` reports its
+/// operands like `assert a == b;`
+pub fn ungrouped(mut expr: &Expr) -> &Expr {
+    while let Expr::Grouping(inner) = expr {
+        expr = inner;
+    }
+    expr
+}
+
+/// Comparisons whose operands are reported when an assertion of them fails
+pub fn is_comparison(kind: TokenType) -> bool {
+    matches!(
+        kind,
+        TokenType::EqEq
+            | TokenType::BangEq
+            | TokenType::Greater
+            | TokenType::GreaterEq
+            | TokenType::Less
+            | TokenType::LessEq
+            | TokenType::In
+    )
+}
+
+pub fn assert_failure(
+    keyword: &TokenAstInfo,
+    source: &str,
+    msg: Option<Value>,
+    operands: Option<(Value, Value)>,
+) -> Error {
+    let mut text = match msg {
+        Some(msg) => format!("Assertion failed: {msg} ({source})"),
+        None => format!("Assertion failed: {source}"),
+    };
+    if let Some((left, right)) = operands {
+        text += &format!("\n  left: {left}\n  right: {right}");
+    }
+
+    RuntimeError {
+        token: keyword.clone(),
+        msg: text,
+    }
+    .into()
+}
+
+pub fn check_set_element(brace: &TokenAstInfo, item: &Value) -> Result<(), Error> {
+    if item.is_hashable() {
+        Ok(())
+    } else {
+        Err(RuntimeError {
+            token: brace.clone(),
+            msg: format!("{item} can't be a set element"),
+        }
+        .into())
+    }
+}
+
+/// Field of a record or element of a tuple, named by its index
+pub fn get_field(object: &Value, name: &TokenAstInfo) -> Result<Value, Error> {
+    let field = name.get_name();
+    let val = match object {
+        Value::Tuple(items) => match field.parse::<usize>() {
+            Ok(i) if i < items.len() => Ok(items[i].clone()),
+            _ => Err(format!(
+                "Index {field} out of range for tuple of length {}",
+                items.len()
+            )),
+        },
+        Value::Record(record) => record
+            .get(field)
+            .cloned()
+            .ok_or_else(|| format!("Undefined field '{field}'")),
+        _ => Err("Only tuples and records have fields".to_string()),
+    };
+
+    val.map_err(|msg| {
+        RuntimeError {
+            token: name.clone(),
+            msg,
+        }
+        .into()
+    })
+}
+
+pub fn is_truthy(val: &Value) -> bool {
     match *val {
         Value::Nil => false,
         Value::Bool(b) => b,
@@ -1,12 +1,16 @@
+use crate::ast::stmt::Stmt;
+use crate::checker::Checker;
 use crate::error::{Error, ParseError, RuntimeError};
 use crate::interpreter::Interpreter;
 use crate::parser::Parser;
 use crate::resolver::Resolver;
 use crate::scanner::Scanner;
 use crate::tokentype::TokenType;
-use std::fs::File;
-use std::io::{self, Read, Result, Write};
+use crate::vm::{chunk::Proto, compiler::Compiler, disassembler::disassemble, loxc};
+use std::fs;
+use std::io::{self, ErrorKind, Result, Write};
 use std::process::exit;
+use std::time::{Duration, Instant};
 
 #[derive(Default)]
 pub struct Lox {
@@ -24,13 +28,89 @@ impl Lox {
         }
     }
 
+    /// Disabled assertions are skipped without evaluating their condition
+    pub fn set_assertions(&mut self, enabled: bool) {
+        self.interpreter.set_assertions(enabled);
+    }
+
+    /// Checks annotated parameters and return values on every call
+    pub fn set_type_hints(&mut self, enabled: bool) {
+        self.interpreter.set_type_hints(enabled);
+    }
+
+    /// Calls nested deeper fail with a "Stack overflow" error
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.interpreter.set_max_call_depth(depth);
+    }
+
+    /// Scripts fail once they evaluated this many statements and expressions,
+    /// or ran this many instructions on the VM
+    pub fn set_step_budget(&mut self, steps: u64) {
+        self.interpreter.set_step_budget(Some(steps));
+    }
+
+    /// Scripts fail once they ran for this long
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.interpreter
+            .set_deadline(Some(Instant::now() + timeout));
+    }
+
+    /// Scripts fail once they use more heap memory than this, in bytes
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.interpreter.set_memory_limit(Some(limit));
+    }
+
+    /// Compiles scripts to bytecode and runs them on the VM
+    pub fn set_bytecode(&mut self, enabled: bool) {
+        self.interpreter.set_bytecode(enabled);
+    }
+
+    /// Runs a script, or a compiled one if the file is a `.loxc` file
     pub fn run_file(&mut self, path: &str) -> Result<()> {
-        let mut file = File::options().read(true).open(path)?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).unwrap();
+        match load(path)? {
+            Script::Compiled(script) => {
+                let res = self.interpreter.run_compiled(*script);
+                self.report_result(res);
+            }
+            Script::Source(source) => self.run(&source),
+        }
+
+        self.exit_on_error();
+
+        Ok(())
+    }
+
+    /// Compiles a script to bytecode and writes it to `out` as a `.loxc` file
+    pub fn compile_file(&mut self, path: &str, out: &str) -> Result<()> {
+        let source = read_source(path)?;
+        if let Some(ast) = self.analyze(&source) {
+            let script = Compiler::new().compile(&ast);
+            fs::write(out, loxc::encode(&script))?;
+        }
+
+        self.exit_on_error();
+
+        Ok(())
+    }
+
+    /// Prints the bytecode of a script or of a `.loxc` file
+    pub fn disassemble_file(&mut self, path: &str) -> Result<()> {
+        let (script, source) = match load(path)? {
+            Script::Compiled(script) => (*script, None),
+            Script::Source(source) => {
+                let Some(ast) = self.analyze(&source) else {
+                    exit(65)
+                };
+                (Compiler::new().compile(&ast), Some(source))
+            }
+        };
+
+        print!("{}", disassemble(&script, source.as_deref()));
 
-        self.run(&buf);
+        Ok(())
+    }
 
+    fn exit_on_error(&self) {
         if self.had_error {
             exit(65)
         }
@@ -38,8 +118,6 @@ impl Lox {
         if self.had_runtime_error {
             exit(70);
         }
-
-        Ok(())
     }
 
     pub fn run_prompt(&mut self) {
@@ -61,16 +139,24 @@ impl Lox {
     }
 
     fn run(&mut self, line: &str) {
-        let scanner = Scanner::new(line);
+        if let Some(ast) = self.analyze(line) {
+            let res = self.interpreter.interpret(ast);
+            self.report_result(res);
+        }
+    }
+
+    /// Parses the source and runs the static checks, reporting their errors
+    fn analyze(&mut self, source: &str) -> Option<Vec<Stmt>> {
+        let scanner = Scanner::new(source);
         let tokens = match scanner.scan_tokens() {
             Ok(t) => t,
             Err(e) => {
                 self.error(e);
-                return;
+                return None;
             }
         };
 
-        let mut parser = Parser::new(tokens);
+        let mut parser = Parser::new(source, tokens);
         let ast = match parser.parse() {
             Ok(ast) => ast,
             Err(e) => {
@@ -78,22 +164,36 @@ impl Lox {
                 for pe in e {
                     self.error(pe.into());
                 }
-                return;
+                return None;
             }
         };
 
-        let mut resolver = Resolver::new(&mut self.interpreter);
-        if let Err(e) = resolver.resolve(&ast) {
+        if let Err(e) = Resolver::new().resolve(&ast) {
             for e in e {
                 self.error(e);
             }
-            return;
+            return None;
         }
 
-        match self.interpreter.interpret(ast) {
-            Ok(_) => (),
-            Err(e) => self.error(e),
-        };
+        if let Err(e) = Checker::new().check(&ast) {
+            for e in e {
+                self.error(e);
+            }
+            return None;
+        }
+
+        Some(ast)
+    }
+
+    /// Reports the error of a finished script and those of its fibers
+    fn report_result(&mut self, res: std::result::Result<(), Error>) {
+        if let Err(e) = res {
+            self.error(e);
+        }
+
+        for e in self.interpreter.take_fiber_errors() {
+            self.error(e);
+        }
     }
 
     fn error(&mut self, err: Error) {
@@ -116,9 +216,16 @@ impl Lox {
                 eprintln!("Error in native function: {msg}");
                 self.had_runtime_error = true;
             }
-            Error::Return(_) => unreachable!(
+            Error::LimitExceeded(limit) => {
+                eprintln!("{limit}");
+                self.had_runtime_error = true;
+            }
+            Error::Return(_) | Error::TailCall(_) => unreachable!(
                 "Well, that shouldn't happen... ICE Code: 0x0: Got return statement as error"
             ),
+            Error::Break(_) | Error::Continue(_) => unreachable!(
+                "Well, that shouldn't happen... ICE Code: 0x7: Got loop exit statement as error"
+            ),
         }
     }
 
@@ -127,3 +234,29 @@ impl Lox {
         self.had_error = true;
     }
 }
+
+enum Script {
+    Source(String),
+    Compiled(Box<Proto>),
+}
+
+fn load(path: &str) -> Result<Script> {
+    let bytes = fs::read(path)?;
+    if loxc::is_loxc(&bytes) {
+        return Ok(Script::Compiled(Box::new(loxc::decode(&bytes)?)));
+    }
+
+    String::from_utf8(bytes)
+        .map(Script::Source)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn read_source(path: &str) -> Result<String> {
+    match load(path)? {
+        Script::Source(source) => Ok(source),
+        Script::Compiled(_) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Already a compiled script",
+        )),
+    }
+}
@@ -1,7 +1,10 @@
+use crate::ast::TokenAstInfo;
 use crate::interpreter::Interpreter;
 use crate::value::Value;
 
 pub trait Callable<O> {
-    fn call(&self, interpreter: &mut Interpreter, args: &[Value]) -> O;
+    /// `paren` is the closing parenthesis of the call, errors about the
+    /// arguments are reported there
+    fn call(&self, interpreter: &mut Interpreter, paren: &TokenAstInfo, args: &[Value]) -> O;
     fn arity(&self) -> usize;
 }
@@ -1,33 +1,90 @@
 pub mod ast;
+pub mod checker;
 pub mod environment;
 pub mod error;
+pub mod event_loop;
+pub mod fiber;
 pub mod function;
+pub mod gc;
+pub mod interner;
 pub mod interpreter;
 pub mod lox;
 pub mod lox_callable;
+pub mod memory;
 pub mod parser;
 pub mod resolver;
 pub mod scanner;
 pub mod test;
 pub mod token;
 pub mod tokentype;
+pub mod unicode;
 pub mod value;
+pub mod vm;
 
+use fiber::FIBER_STACK_SIZE;
 use lox::Lox;
-use std::{env::args, io::Result, process::exit};
+use memory::CountingAllocator;
+use std::{
+    env::args, io::Result, panic, path::Path, process::exit, str::FromStr, thread, time::Duration,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 fn main() -> Result<()> {
-    let args: Vec<String> = args().collect();
+    thread::Builder::new()
+        .stack_size(FIBER_STACK_SIZE)
+        .spawn(run)?
+        .join()
+        .unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+fn run() -> Result<()> {
     let mut lox = Lox::new();
+    let mut positional = vec![];
+
+    for arg in args().skip(1) {
+        match (arg.as_str(), arg.split_once('=')) {
+            ("--no-assert", _) => lox.set_assertions(false),
+            ("--check-types", _) => lox.set_type_hints(true),
+            ("--bytecode", _) => lox.set_bytecode(true),
+            (_, Some(("--max-call-depth", depth))) => lox.set_max_call_depth(parse(depth)),
+            (_, Some(("--step-budget", steps))) => lox.set_step_budget(parse(steps)),
+            (_, Some(("--memory-limit", bytes))) => lox.set_memory_limit(parse(bytes)),
+            (_, Some(("--timeout", secs))) => match Duration::try_from_secs_f64(parse(secs)) {
+                Ok(timeout) => lox.set_timeout(timeout),
+                Err(_) => usage(),
+            },
+            (flag, _) if flag.starts_with("--") => usage(),
+            _ => positional.push(arg),
+        }
+    }
 
-    match args.len() {
-        1 => lox.run_prompt(),
-        2 => lox.run_file(&args[1])?,
-        _ => {
-            println!("Usage: oxylox [script]");
-            exit(64);
+    match &positional[..] {
+        [] => lox.run_prompt(),
+        [command, script] if command == "compile" => {
+            let out = Path::new(script).with_extension("loxc");
+            lox.compile_file(script, &out.to_string_lossy())?
         }
+        [command, script, out] if command == "compile" => lox.compile_file(script, out)?,
+        [command, script] if command == "disassemble" => lox.disassemble_file(script)?,
+        [command, ..] if command == "compile" || command == "disassemble" => usage(),
+        [script] => lox.run_file(script)?,
+        _ => usage(),
     }
 
     Ok(())
 }
+
+fn parse<T: FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    println!("Usage: oxylox [--no-assert] [--check-types] [--bytecode]");
+    println!("              [--max-call-depth=<n>] [--step-budget=<n>] [--timeout=<seconds>]");
+    println!("              [--memory-limit=<bytes>] [script]");
+    println!("       oxylox compile <script> [output]");
+    println!("       oxylox disassemble <script>");
+    exit(64);
+}
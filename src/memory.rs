@@ -0,0 +1,145 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+};
+
+/// Reported by the `memory` native, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub current: usize,
+    pub peak: usize,
+}
+
+/// Heap memory allocated by the scripts of an interpreter and its fibers:
+/// strings, environments, closures and everything else created while they
+/// run. Memory allocated before, like the script itself, isn't counted, so
+/// freeing it doesn't take `current` below zero.
+#[derive(Debug)]
+pub struct Meter {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            current: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+        }
+    }
+}
+
+impl Meter {
+    pub fn usage(&self) -> Usage {
+        Usage {
+            current: self.current.load(Relaxed),
+            peak: self.peak.load(Relaxed),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(usize::MAX), Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        Some(self.limit.load(Relaxed)).filter(|&limit| limit != usize::MAX)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.current.load(Relaxed) > self.limit.load(Relaxed)
+    }
+
+    fn allocated(&self, size: usize) {
+        let current = self.current.fetch_add(size, Relaxed) + size;
+        self.peak.fetch_max(current, Relaxed);
+    }
+
+    fn freed(&self, size: usize) {
+        let _ = (self.current).fetch_update(Relaxed, Relaxed, |current| {
+            Some(current.saturating_sub(size))
+        });
+    }
+}
+
+thread_local! {
+    /// Meter of the interpreter running on this thread, null when none is
+    static METER: Cell<*const Meter> = const { Cell::new(ptr::null()) };
+}
+
+/// Charges allocations of this thread to a meter until dropped
+pub struct Metering {
+    /// Kept alive while this thread may charge it
+    _meter: Arc<Meter>,
+    previous: *const Meter,
+}
+
+impl Metering {
+    pub fn start(meter: &Arc<Meter>) -> Self {
+        let meter = Arc::clone(meter);
+        let previous = METER.with(|current| current.replace(Arc::as_ptr(&meter)));
+        Self {
+            _meter: meter,
+            previous,
+        }
+    }
+}
+
+impl Drop for Metering {
+    fn drop(&mut self) {
+        METER.with(|current| current.set(self.previous));
+    }
+}
+
+fn with_meter(f: impl FnOnce(&Meter)) {
+    // The thread local is gone while the thread is torn down
+    let _ = METER.try_with(|current| {
+        // Only set by `Metering`, which keeps the meter alive meanwhile
+        if let Some(meter) = unsafe { current.get().as_ref() } {
+            f(meter);
+        }
+    });
+}
+
+/// System allocator charging every allocation to the meter of the thread
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            with_meter(|meter| meter.allocated(layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        with_meter(|meter| meter.freed(layout.size()));
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            with_meter(|meter| meter.allocated(layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            with_meter(|meter| {
+                meter.freed(layout.size());
+                meter.allocated(new_size);
+            });
+        }
+        new
+    }
+}
@@ -1,5 +1,11 @@
+use std::cell::Cell;
+
 use crate::{
-    ast::{stmt::Stmt, *},
+    ast::{
+        stmt::{Clause, Contracts, Stmt},
+        types::{Signature, Type, TypeAnn},
+        *,
+    },
     error::ParseError,
     token::Token,
     tokentype::TokenType::{self, *},
@@ -9,14 +15,20 @@ use crate::{
 type StmtRes = Result<Stmt, ParseError>;
 type ExprRes = Result<Expr, ParseError>;
 
-pub struct Parser {
+pub struct Parser<'a> {
+    /// The text the tokens were scanned from, quoted by assertions
+    source: &'a str,
     tokens: Vec<Token>,
     cur: usize,
 }
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, cur: 0 }
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a str, tokens: Vec<Token>) -> Self {
+        Self {
+            source,
+            tokens,
+            cur: 0,
+        }
     }
 
     pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<ParseError>> {
@@ -66,6 +78,13 @@ impl Parser {
         self.peek().r#type == token
     }
 
+    fn check_next(&self, token: TokenType) -> bool {
+        self.tokens
+            .get(self.cur + 1)
+            .filter(|t| t.r#type == token)
+            .is_some()
+    }
+
     fn match_any(&mut self, types: &[TokenType]) -> bool {
         for t in types {
             if self.check(*t) {
@@ -80,19 +99,78 @@ impl Parser {
         if self.match_any(&[Var]) {
             self.var_declaration()
         } else if self.match_any(&[Fun]) {
-            self.function_declaration("function")
+            self.function_declaration("function", false, vec![])
+        } else if self.check(Async) && self.check_next(Fun) {
+            self.advance();
+            self.advance();
+            self.function_declaration("function", true, vec![])
+        } else if self.check(At) {
+            self.decorated_declaration()
         } else {
             self.statement()
         }
     }
 
-    fn function_declaration(&mut self, kind: &str) -> StmtRes {
+    /// Decorators are call expressions, so `@retry(3)` applies the result
+    /// of `retry(3)`
+    fn decorated_declaration(&mut self) -> StmtRes {
+        let mut decorators = vec![];
+        while self.match_any(&[At]) {
+            let at = self.previous().into();
+            decorators.push((at, self.call()?));
+        }
+
+        let is_async = self.match_any(&[Async]);
+        self.consume(Fun, "Expect function declaration after decorator")?;
+        self.function_declaration("function", is_async, decorators)
+    }
+
+    fn function_declaration(
+        &mut self,
+        kind: &str,
+        is_async: bool,
+        decorators: Vec<(TokenAstInfo, Expr)>,
+    ) -> StmtRes {
         let name = self
             .consume(Identifier, &format!("Expect {kind} name"))?
             .into();
         self.consume(LeftParen, &format!("Expect '(' after {kind} name"))?;
+        let (params, signature) = self.parameters()?;
+
+        let mut contracts = Contracts::default();
+        while self.match_any(&[Requires, Ensures]) {
+            let keyword: TokenAstInfo = self.previous().into();
+            let start = self.cur;
+            let cond = self.expression()?;
+            let clause = Clause {
+                keyword,
+                cond,
+                source: self.source_text(start),
+            };
+            if clause.keyword.kind == Requires {
+                contracts.requires.push(clause);
+            } else {
+                contracts.ensures.push(clause);
+            }
+        }
 
+        self.consume(LeftBrace, &format!("Expect '{{' before {kind} body"))?;
+        let body = self.block()?;
+        Ok(Stmt::Function {
+            name,
+            params,
+            signature,
+            body,
+            is_async,
+            decorators,
+            contracts,
+        })
+    }
+
+    /// Parses parameters after the opening paren up to the optional return type
+    fn parameters(&mut self) -> Result<(Vec<TokenAstInfo>, Signature), ParseError> {
         let mut params = vec![];
+        let mut signature = Signature::default();
         if !self.check(RightParen) {
             loop {
                 if params.len() >= 255 {
@@ -103,32 +181,97 @@ impl Parser {
                 }
 
                 params.push(self.consume(Identifier, "Expect parameter name")?.into());
+                signature.params.push(self.type_annotation()?);
                 if !self.match_any(&[Comma]) {
                     break;
                 }
             }
         }
         self.consume(RightParen, "Expect ')' after parameters")?;
+        signature.ret = self.type_annotation()?;
 
-        self.consume(LeftBrace, &format!("Expect '{{' before {kind} body"))?;
-        let body = self.block()?;
-        Ok(Stmt::Function { name, params, body })
+        Ok((params, signature))
+    }
+
+    /// Parses `: type` if present
+    fn type_annotation(&mut self) -> Result<Option<TypeAnn>, ParseError> {
+        if !self.match_any(&[Colon]) {
+            return Ok(None);
+        }
+
+        if !self.match_any(&[Identifier, Nil, Fun]) {
+            return Err(ParseError {
+                token: self.peek().into(),
+                msg: "Expect type after ':'".into(),
+            });
+        }
+
+        let token = self.previous();
+        match Type::from_name(&token.lexeme) {
+            Some(tpe) => Ok(Some(TypeAnn {
+                token: token.into(),
+                tpe,
+            })),
+            None => Err(ParseError {
+                token: token.into(),
+                msg: format!("Unknown type '{}'", token.lexeme),
+            }),
+        }
     }
 
     fn var_declaration(&mut self) -> StmtRes {
         let name = self.consume(Identifier, "Expect variable name")?.into();
+        let tpe = self.type_annotation()?;
         let mut initializer = Expr::Literal(Value::Nil);
         if self.match_any(&[Eq]) {
             initializer = self.expression()?;
         }
 
         self.consume(Semicolon, "Expect ';' after variable declaration")?;
-        Ok(Stmt::Var { name, initializer })
+        Ok(Stmt::Var {
+            name,
+            tpe,
+            initializer,
+        })
     }
 
     fn statement(&mut self) -> StmtRes {
+        let mut label = None;
+        if self.check(Identifier) && self.check_next(Colon) {
+            label = Some(self.advance().into());
+            self.advance();
+            if ![For, While, Do, Loop].contains(&self.peek().r#type) {
+                return Err(ParseError {
+                    token: self.peek().into(),
+                    msg: "Expect loop after label".into(),
+                });
+            }
+        }
+
         if self.match_any(&[For]) {
-            return self.for_statement();
+            return self.for_statement(label);
+        }
+
+        if self.match_any(&[While]) {
+            return self.while_statement(label);
+        }
+
+        if self.match_any(&[Do]) {
+            return self.do_while_statement(label);
+        }
+
+        if self.match_any(&[Loop]) {
+            let body = Box::new(self.statement()?);
+            return Ok(Stmt::While {
+                cond: Expr::Literal(Value::Bool(true)),
+                body,
+                inc: None,
+                label,
+            });
+        }
+
+        if self.match_any(&[Break, Continue]) {
+            return self.loop_exit_statement();
         }
 
         if self.match_any(&[If]) {
@@ -143,8 +286,12 @@ impl Parser {
             return self.return_statement();
         }
 
-        if self.match_any(&[While]) {
-            return self.while_statement();
+        if self.match_any(&[Defer]) {
+            return self.defer_statement();
+        }
+
+        if self.match_any(&[Assert]) {
+            return self.assert_statement();
         }
 
         if self.match_any(&[LeftBrace]) {
@@ -165,7 +312,55 @@ impl Parser {
         Ok(Stmt::Return { keyword, val })
     }
 
-    fn for_statement(&mut self) -> StmtRes {
+    fn assert_statement(&mut self) -> StmtRes {
+        let keyword = self.previous().into();
+        let start = self.cur;
+        let cond = self.expression()?;
+        let source = self.source_text(start);
+
+        let mut msg = None;
+        if self.match_any(&[Comma]) {
+            msg = Some(self.expression()?);
+        }
+
+        self.consume(Semicolon, "Expect ';' after assertion")?;
+        Ok(Stmt::Assert {
+            keyword,
+            cond,
+            msg,
+            source,
+        })
+    }
+
+    /// Source text of the tokens consumed since `start`
+    fn source_text(&self, start: usize) -> std::string::String {
+        let first = self.tokens[start].start;
+        let end = self.previous().pos;
+        self.source.chars().skip(first).take(end - first).collect()
+    }
+
+    fn defer_statement(&mut self) -> StmtRes {
+        let expr = self.expression()?;
+        self.consume(Semicolon, "Expect ';' after deferred expression")?;
+        Ok(Stmt::Defer(expr))
+    }
+
+    fn loop_exit_statement(&mut self) -> StmtRes {
+        let keyword: TokenAstInfo = self.previous().into();
+        let mut label = None;
+        if self.match_any(&[Identifier]) {
+            label = Some(self.previous().into());
+        }
+
+        self.consume(Semicolon, &format!("Expect ';' after '{}'", keyword.kind))?;
+        Ok(if keyword.kind == Break {
+            Stmt::Break { keyword, label }
+        } else {
+            Stmt::Continue { keyword, label }
+        })
+    }
+
+    fn for_statement(&mut self, label: Option<TokenAstInfo>) -> StmtRes {
         self.consume(LeftParen, "Expect '(' after 'for'")?;
 
         let init;
@@ -189,22 +384,20 @@ impl Parser {
         }
         self.consume(RightParen, "Expect ')' after for clauses")?;
 
-        let mut body = self.statement()?;
-
-        if inc.is_some() {
-            body = Stmt::Block(vec![body, Stmt::Expr(inc.unwrap())]);
-        }
+        let body = self.statement()?;
 
         if cond.is_none() {
             cond = Some(Expr::Literal(Value::Bool(true)));
         }
-        body = Stmt::While {
+        let mut body = Stmt::While {
             cond: cond.unwrap(),
             body: Box::new(body),
+            inc,
+            label,
         };
 
-        if init.is_some() {
-            body = Stmt::Block(vec![init.unwrap(), body]);
+        if let Some(init) = init {
+            body = Stmt::Block(vec![init, body]);
         }
 
         Ok(body)
@@ -254,13 +447,29 @@ impl Parser {
         Ok(Stmt::Print(exprs))
     }
 
-    fn while_statement(&mut self) -> StmtRes {
+    fn while_statement(&mut self, label: Option<TokenAstInfo>) -> StmtRes {
         self.consume(LeftParen, "Expect '(' after while")?;
         let cond = self.expression()?;
         self.consume(RightParen, "Expect ')' after while condition")?;
         let body = Box::new(self.statement()?);
 
-        Ok(Stmt::While { cond, body })
+        Ok(Stmt::While {
+            cond,
+            body,
+            inc: None,
+            label,
+        })
+    }
+
+    fn do_while_statement(&mut self, label: Option<TokenAstInfo>) -> StmtRes {
+        let body = Box::new(self.statement()?);
+        self.consume(While, "Expect 'while' after do body")?;
+        self.consume(LeftParen, "Expect '(' after while")?;
+        let cond = self.expression()?;
+        self.consume(RightParen, "Expect ')' after while condition")?;
+        self.consume(Semicolon, "Expect ';' after do-while statement")?;
+
+        Ok(Stmt::DoWhile { body, cond, label })
     }
 
     fn expression_statement(&mut self) -> StmtRes {
@@ -280,10 +489,16 @@ impl Parser {
             let equals = self.previous().into();
             let val = self.assignment()?;
 
-            return if let Expr::Variable(name) = expr {
+            return if let Expr::Variable { name, local } = expr {
                 Ok(Expr::Assign {
                     name,
                     val: Box::new(val),
+                    local,
+                })
+            } else if let Expr::Get { .. } = expr {
+                Err(ParseError {
+                    token: equals,
+                    msg: "Can't assign to a field of an immutable value".into(),
                 })
             } else {
                 Err(ParseError {
@@ -329,11 +544,11 @@ impl Parser {
     }
 
     fn equality(&mut self) -> ExprRes {
-        let mut expr = self.comparsion()?;
+        let mut expr = self.pipeline()?;
 
         while self.match_any(&[TokenType::BangEq, TokenType::EqEq]) {
             let op = self.previous().into();
-            let right = self.comparsion()?;
+            let right = self.pipeline()?;
             expr = Expr::Binary {
                 op,
                 left: Box::new(expr),
@@ -344,10 +559,48 @@ impl Parser {
         Ok(expr)
     }
 
+    /// `x |> f(a, b)` is lowered to `f(x, a, b)` and `x |> f` to `f(x)`
+    fn pipeline(&mut self) -> ExprRes {
+        let mut expr = self.comparsion()?;
+
+        while self.match_any(&[PipeGreater]) {
+            let op: TokenAstInfo = self.previous().into();
+            let right = self.comparsion()?;
+            expr = match right {
+                Expr::Call {
+                    callee,
+                    paren,
+                    mut args,
+                } => {
+                    if args.len() >= 255 {
+                        return Err(ParseError {
+                            token: paren,
+                            msg: "Can't have more than 255 arguments".into(),
+                        });
+                    }
+
+                    args.insert(0, expr);
+                    Expr::Call {
+                        callee,
+                        paren,
+                        args,
+                    }
+                }
+                callee => Expr::Call {
+                    callee: Box::new(callee),
+                    paren: op,
+                    args: vec![expr],
+                },
+            };
+        }
+
+        Ok(expr)
+    }
+
     fn comparsion(&mut self) -> ExprRes {
         let mut expr = self.term()?;
 
-        while self.match_any(&[Greater, GreaterEq, Less, LessEq]) {
+        while self.match_any(&[Greater, GreaterEq, Less, LessEq, In]) {
             let op = self.previous().into();
             let right = self.term()?;
             expr = Expr::Binary {
@@ -393,6 +646,15 @@ impl Parser {
     }
 
     fn unary(&mut self) -> ExprRes {
+        if self.match_any(&[Await]) {
+            let keyword = self.previous().into();
+            let expr = self.unary()?;
+            return Ok(Expr::Await {
+                keyword,
+                expr: Box::new(expr),
+            });
+        }
+
         if self.match_any(&[Bang, Minus]) {
             let op = self.previous().into();
             let right = self.unary()?;
@@ -411,6 +673,8 @@ impl Parser {
         loop {
             if self.match_any(&[LeftParen]) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_any(&[Dot]) {
+                expr = self.field(expr)?;
             } else {
                 break;
             }
@@ -419,6 +683,36 @@ impl Parser {
         Ok(expr)
     }
 
+    fn field(&mut self, object: Expr) -> ExprRes {
+        if self.match_any(&[Identifier]) {
+            return Ok(Expr::Get {
+                object: Box::new(object),
+                name: self.previous().into(),
+            });
+        }
+
+        let token = self.consume(Number, "Expect field name or index after '.'")?;
+        let mut info = TokenAstInfo::from(token);
+        // `t.0.1` is scanned as the number `0.1`, so it is split into two indexes
+        let mut expr = object;
+        for index in token.lexeme.split('.') {
+            if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+                return Err(ParseError {
+                    token: info,
+                    msg: "Invalid tuple index".into(),
+                });
+            }
+
+            info.name = Some(index.into());
+            expr = Expr::Get {
+                object: Box::new(expr),
+                name: info.clone(),
+            };
+        }
+
+        Ok(expr)
+    }
+
     fn finish_call(&mut self, callee: Expr) -> ExprRes {
         let mut args = vec![];
         if !self.check(RightParen) {
@@ -461,50 +755,150 @@ impl Parser {
         }
 
         if self.match_any(&[Fun]) {
-            return self.function_expr();
+            return self.function_expr(false);
+        }
+
+        if self.match_any(&[Async]) {
+            self.consume(Fun, "Expect 'fun' after 'async'")?;
+            return self.function_expr(true);
         }
 
         if self.match_any(&[Identifier]) {
-            return Ok(Expr::Variable(self.previous().into()));
+            return Ok(Expr::Variable {
+                name: self.previous().into(),
+                local: Cell::default(),
+            });
         }
 
         if self.match_any(&[LeftParen]) {
+            if self.match_any(&[RightParen]) {
+                return Ok(Expr::Tuple(vec![]));
+            }
+
             let expr = self.expression()?;
+            if self.match_any(&[Comma]) {
+                return self.tuple(expr);
+            }
+
             self.consume(RightParen, "Expect ')' after expression")?;
             return Ok(Expr::Grouping(Box::new(expr)));
         }
 
+        if self.match_any(&[LeftBrace]) {
+            return self.record();
+        }
+
+        if self.match_any(&[HashBrace]) {
+            let brace = self.previous().into();
+            let mut items = vec![];
+            while !self.check(RightBrace) {
+                items.push(self.expression()?);
+                if !self.match_any(&[Comma]) {
+                    break;
+                }
+            }
+
+            self.consume(RightBrace, "Expect '}' after set elements")?;
+            return Ok(Expr::Set { brace, items });
+        }
+
         Err(ParseError {
             token: self.peek().into(),
             msg: "Expect expression".to_string(),
         })
     }
 
-    fn function_expr(&mut self) -> Result<Expr, ParseError> {
-        self.consume(LeftParen, "Expected '(' in function expression")?;
+    /// Parses the rest of a tuple after its first element and comma, so `(1,)`
+    /// is a tuple while `(1)` stays a grouping
+    fn tuple(&mut self, first: Expr) -> ExprRes {
+        let mut items = vec![first];
+        while !self.check(RightParen) {
+            items.push(self.expression()?);
+            if !self.match_any(&[Comma]) {
+                break;
+            }
+        }
 
-        let mut params = vec![];
-        if !self.check(RightParen) {
-            loop {
-                if params.len() >= 255 {
-                    return Err(ParseError {
-                        token: self.peek().into(),
-                        msg: "Can't have more than 255 parametres".into(),
-                    });
-                }
+        self.consume(RightParen, "Expect ')' after tuple elements")?;
+        Ok(Expr::Tuple(items))
+    }
 
-                params.push(self.consume(Identifier, "Expect parameter name")?.into());
-                if !self.match_any(&[Comma]) {
-                    break;
-                }
+    fn record(&mut self) -> ExprRes {
+        let mut fields: Vec<(TokenAstInfo, Expr)> = vec![];
+        while !self.check(RightBrace) {
+            let name: TokenAstInfo = self.consume(Identifier, "Expect field name")?.into();
+            if fields.iter().any(|(field, _)| field.name == name.name) {
+                return Err(ParseError {
+                    token: name,
+                    msg: "Duplicate field in record".into(),
+                });
+            }
+
+            self.consume(Colon, "Expect ':' after field name")?;
+            fields.push((name, self.expression()?));
+            if !self.match_any(&[Comma]) {
+                break;
             }
         }
 
-        self.consume(RightParen, "Expect ')' after parameters")?;
+        self.consume(RightBrace, "Expect '}' after record fields")?;
+        Ok(Expr::Record(fields))
+    }
 
-        self.consume(LeftBrace, "Expect '{{' before lambda body")?;
-        let body = self.block()?;
-        Ok(Expr::Lambda { params, body })
+    fn function_expr(&mut self, is_async: bool) -> Result<Expr, ParseError> {
+        self.consume(LeftParen, "Expected '(' in function expression")?;
+        let (params, signature) = self.parameters()?;
+
+        if self.match_any(&[FatArrow]) {
+            let keyword = self.previous().into();
+            let val = self.expression()?;
+            return Ok(Expr::Lambda {
+                params,
+                signature,
+                body: vec![Stmt::Return { keyword, val }],
+                is_async,
+            });
+        }
+
+        self.consume(LeftBrace, "Expect '{' or '=>' before lambda body")?;
+        let body = self.lambda_block()?;
+        Ok(Expr::Lambda {
+            params,
+            signature,
+            body,
+            is_async,
+        })
+    }
+
+    /// Same as `block`, but a trailing expression without ';' before the
+    /// closing brace becomes the lambda's result
+    fn lambda_block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut statements = vec![];
+
+        while !self.check(RightBrace) && !self.is_at_end() {
+            let starts_statement = [
+                Var, Fun, Async, At, For, If, Print, Return, Defer, Assert, While, Do, Loop, Break,
+                Continue, LeftBrace,
+            ]
+            .contains(&self.peek().r#type);
+            if starts_statement || (self.check(Identifier) && self.check_next(Colon)) {
+                statements.push(self.declaration()?);
+                continue;
+            }
+
+            let expr = self.expression()?;
+            if self.check(RightBrace) {
+                let keyword = self.peek().into();
+                statements.push(Stmt::Return { keyword, val: expr });
+                break;
+            }
+
+            self.consume(Semicolon, "Expect ';' after value")?;
+            statements.push(Stmt::Expr(expr));
+        }
+
+        self.consume(RightBrace, "Expect '}' after block")?;
+        Ok(statements)
     }
 
     fn consume(&mut self, token: TokenType, msg: &str) -> Result<&Token, ParseError> {
@@ -527,7 +921,8 @@ impl Parser {
             }
 
             match self.peek().r#type {
-                Class | Fun | Var | For | If | While | Print | Return => return,
+                Class | Fun | Async | At | Var | For | If | While | Do | Loop | Break
+                | Continue | Print | Return | Defer | Assert => return,
                 _ => (),
             }
 
@@ -1,31 +1,43 @@
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+};
 
 use crate::{
     ast::{stmt::Stmt, visitor::Visitor, Expr, TokenAstInfo},
+    environment::Local,
     error::{Error, ParseError},
-    interpreter::Interpreter,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 enum FunctionType {
     Lambda,
     Fun,
+    Async,
+    #[default]
     None,
 }
 
-pub struct Resolver<'a> {
-    interpreter: &'a mut Interpreter,
-    scopes: Vec<HashMap<String, bool>>,
+/// Slots of the variables declared in a scope, in declaration order
+#[derive(Default)]
+struct Scope {
+    slots: HashMap<String, usize>,
+    /// A variable is only visible once its initializer has run
+    defined: HashSet<String>,
+}
+
+#[derive(Default)]
+pub struct Resolver {
+    scopes: Vec<Scope>,
     cur_function: FunctionType,
+    /// Labels of the loops enclosing the current statement, `None` for
+    /// unlabeled loops
+    loops: Vec<Option<String>>,
 }
 
-impl<'a> Resolver<'a> {
-    pub fn new(interpreter: &'a mut Interpreter) -> Self {
-        Self {
-            scopes: vec![],
-            interpreter,
-            cur_function: FunctionType::None,
-        }
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
     }
 
     pub fn resolve(&mut self, stmts: &[Stmt]) -> Result<(), Vec<Error>> {
@@ -34,6 +46,10 @@ impl<'a> Resolver<'a> {
         for stmt in stmts {
             if let Err(e) = self.visit_statement(stmt) {
                 errors.push(e);
+                // The failed statement may have left its scopes and loops open
+                self.scopes.clear();
+                self.loops.clear();
+                self.cur_function = FunctionType::None;
             }
         }
 
@@ -45,7 +61,7 @@ impl<'a> Resolver<'a> {
     }
 
     fn begin_scope(&mut self) {
-        self.scopes.push(HashMap::new())
+        self.scopes.push(Scope::default())
     }
 
     fn end_scope(&mut self) {
@@ -53,49 +69,112 @@ impl<'a> Resolver<'a> {
     }
 
     fn declare(&mut self, name: &TokenAstInfo) -> Result<(), Error> {
-        match self.scopes.last_mut() {
-            Some(scope) => {
-                if scope.insert(name.to_string(), false).is_some() {
-                    return Err(Error::ParseError(ParseError {
-                        token: name.clone(),
-                        msg: "Already a variable with this name in this scope".into(),
-                    }));
-                }
+        if let Some(scope) = self.scopes.last_mut() {
+            let slot = scope.slots.len();
+            if scope.slots.insert(name.get_name().into(), slot).is_some() {
+                return Err(Error::ParseError(ParseError {
+                    token: name.clone(),
+                    msg: "Already a variable with this name in this scope".into(),
+                }));
             }
-            None => (),
         }
 
         Ok(())
     }
 
+    /// Makes a declared variable visible, `result` is defined without a
+    /// declaration
     fn define(&mut self, name: &str) {
-        match self.scopes.last_mut() {
-            Some(scope) => {
-                scope.insert(name.to_string(), true);
-            }
-            None => (),
+        if let Some(scope) = self.scopes.last_mut() {
+            let slot = scope.slots.len();
+            scope.slots.entry(name.into()).or_insert(slot);
+            scope.defined.insert(name.into());
         }
     }
 
-    fn resolve_local(&mut self, name: &TokenAstInfo) {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(name.get_name()) {
-                self.interpreter.resolve(name, i);
+    fn resolve_local(&mut self, name: &TokenAstInfo, local: &Cell<Option<Local>>) {
+        for (distance, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.defined.contains(name.get_name()) {
+                let slot = scope.slots[name.get_name()];
+                local.set(Some(Local { distance, slot }));
                 return;
             }
         }
     }
 
+    fn resolve_loop(&mut self, label: &Option<TokenAstInfo>, body: &Stmt) -> Result<(), Error> {
+        let name = label.as_ref().map(|l| l.get_name().to_string());
+        if name.is_some() && self.loops.contains(&name) {
+            return Err(ParseError {
+                token: label.clone().unwrap(),
+                msg: "Label shadows a label of an enclosing loop".into(),
+            }
+            .into());
+        }
+
+        self.loops.push(name);
+        self.visit_statement(body)?;
+        self.loops.pop();
+
+        Ok(())
+    }
+
+    fn resolve_loop_exit(
+        &mut self,
+        keyword: &TokenAstInfo,
+        label: &Option<TokenAstInfo>,
+    ) -> Result<(), Error> {
+        if self.loops.is_empty() {
+            return Err(ParseError {
+                token: keyword.clone(),
+                msg: format!("Can't use '{}' outside of a loop", keyword.kind),
+            }
+            .into());
+        }
+
+        if let Some(label) = label {
+            if !self.loops.contains(&Some(label.get_name().to_string())) {
+                return Err(ParseError {
+                    token: label.clone(),
+                    msg: format!("Undefined label '{}'", label.get_name()),
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
     fn resolve_function(&mut self, fun: &Stmt, fun_type: FunctionType) -> Result<(), Error> {
         let enclosing = self.cur_function;
+        let enclosing_loops = std::mem::take(&mut self.loops);
         self.cur_function = fun_type;
         self.begin_scope();
-        if let Stmt::Function { params, body, .. } = fun {
+        if let Stmt::Function {
+            params,
+            body,
+            contracts,
+            ..
+        } = fun
+        {
             for param in params {
                 self.declare(param)?;
                 self.define(param.get_name());
             }
 
+            for clause in &contracts.requires {
+                self.visit_expression(&clause.cond)?;
+            }
+
+            // Postconditions see the parameters and `result`, but not the
+            // locals of the body
+            self.begin_scope();
+            self.define("result");
+            for clause in &contracts.ensures {
+                self.visit_expression(&clause.cond)?;
+            }
+            self.end_scope();
+
             for stmt in body {
                 self.visit_statement(stmt)?;
             }
@@ -104,15 +183,17 @@ impl<'a> Resolver<'a> {
         }
         self.end_scope();
         self.cur_function = enclosing;
+        self.loops = enclosing_loops;
 
         Ok(())
     }
 
     fn resolve_lambda(&mut self, fun: &Expr, fun_type: FunctionType) -> Result<(), Error> {
         let enclosing = self.cur_function;
+        let enclosing_loops = std::mem::take(&mut self.loops);
         self.cur_function = fun_type;
         self.begin_scope();
-        if let Expr::Lambda { body, params } = fun {
+        if let Expr::Lambda { body, params, .. } = fun {
             for param in params {
                 self.declare(param)?;
                 self.define(param.get_name());
@@ -127,22 +208,23 @@ impl<'a> Resolver<'a> {
 
         self.end_scope();
         self.cur_function = enclosing;
+        self.loops = enclosing_loops;
 
         Ok(())
     }
 }
 
-impl<'a> Visitor for Resolver<'a> {
+impl Visitor for Resolver {
     type Output = Result<(), Error>;
 
     fn visit_expression(&mut self, expr: &crate::ast::Expr) -> Self::Output {
         match expr {
-            Expr::Variable(token) => {
-                self.resolve_local(token);
+            Expr::Variable { name, local } => {
+                self.resolve_local(name, local);
             }
-            Expr::Assign { name, val } => {
+            Expr::Assign { name, val, local } => {
                 self.visit_expression(val)?;
-                self.resolve_local(name)
+                self.resolve_local(name, local)
             }
             Expr::Binary { left, right, .. } => {
                 self.visit_expression(left)?;
@@ -166,9 +248,37 @@ impl<'a> Visitor for Resolver<'a> {
                     self.visit_expression(arg)?;
                 }
             }
-            Expr::Lambda { .. } => {
-                self.resolve_lambda(expr, FunctionType::Lambda)?;
+            Expr::Lambda { is_async, .. } => {
+                let fun_type = if *is_async {
+                    FunctionType::Async
+                } else {
+                    FunctionType::Lambda
+                };
+                self.resolve_lambda(expr, fun_type)?;
+            }
+            Expr::Await { keyword, expr } => {
+                // Top-level code may await too, the event loop runs meanwhile
+                if let FunctionType::Fun | FunctionType::Lambda = self.cur_function {
+                    return Err(ParseError {
+                        token: keyword.clone(),
+                        msg: "Can't use 'await' outside of an async function".into(),
+                    }
+                    .into());
+                }
+
+                self.visit_expression(expr)?;
+            }
+            Expr::Tuple(items) | Expr::Set { items, .. } => {
+                for item in items {
+                    self.visit_expression(item)?;
+                }
+            }
+            Expr::Record(fields) => {
+                for (_, val) in fields {
+                    self.visit_expression(val)?;
+                }
             }
+            Expr::Get { object, .. } => self.visit_expression(object)?,
         }
 
         Ok(())
@@ -183,16 +293,33 @@ impl<'a> Visitor for Resolver<'a> {
                 }
                 self.end_scope();
             }
-            Stmt::Var { name, initializer } => {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
                 self.declare(name)?;
                 self.visit_expression(initializer)?;
                 self.define(name.get_name());
             }
-            Stmt::Function { name, .. } => {
+            Stmt::Function {
+                name,
+                is_async,
+                decorators,
+                ..
+            } => {
+                // Decorators run before the name is bound
+                for (_, decorator) in decorators {
+                    self.visit_expression(decorator)?;
+                }
+
                 self.declare(name)?;
                 self.define(name.get_name());
 
-                self.resolve_function(stmt, FunctionType::Fun)?;
+                let fun_type = if *is_async {
+                    FunctionType::Async
+                } else {
+                    FunctionType::Fun
+                };
+                self.resolve_function(stmt, fun_type)?;
             }
             Stmt::Expr(expr) => self.visit_expression(expr)?,
             Stmt::If {
@@ -217,15 +344,37 @@ impl<'a> Visitor for Resolver<'a> {
 
                 self.visit_expression(val)?;
             }
-            Stmt::While { cond, body } => {
+            Stmt::While {
+                cond,
+                body,
+                inc,
+                label,
+            } => {
                 self.visit_expression(cond)?;
-                self.visit_statement(body)?;
+                self.resolve_loop(label, body)?;
+                if let Some(inc) = inc {
+                    self.visit_expression(inc)?;
+                }
+            }
+            Stmt::DoWhile { body, cond, label } => {
+                self.resolve_loop(label, body)?;
+                self.visit_expression(cond)?;
+            }
+            Stmt::Break { keyword, label } | Stmt::Continue { keyword, label } => {
+                self.resolve_loop_exit(keyword, label)?;
             }
             Stmt::Print(exprs) => {
                 for expr in exprs {
                     self.visit_expression(expr)?;
                 }
             }
+            Stmt::Defer(expr) => self.visit_expression(expr)?,
+            Stmt::Assert { cond, msg, .. } => {
+                self.visit_expression(cond)?;
+                if let Some(msg) = msg {
+                    self.visit_expression(msg)?;
+                }
+            }
         };
 
         Ok(())
@@ -1,7 +1,9 @@
 use crate::{
     error::{Error, ScanError},
+    interner::intern,
     token::Token,
     tokentype::{TokenType, KEYWORDS},
+    unicode,
     value::Value,
 };
 
@@ -43,6 +45,7 @@ impl<'a> Scanner<'a> {
             "".to_string(),
             Value::Nil,
             self.line,
+            self.start,
             self.current,
         ));
 
@@ -63,19 +66,26 @@ impl<'a> Scanner<'a> {
             '{' => self.add_token(LeftBrace),
             '}' => self.add_token(RightBrace),
             ',' => self.add_token(Comma),
+            ':' => self.add_token(Colon),
             '.' => self.add_token(Dot),
             '-' => self.add_token(Minus),
             '+' => self.add_token(Plus),
             ';' => self.add_token(Semicolon),
             '*' => self.add_token(Star),
             '%' => self.add_token(Percent),
+            '@' => self.add_token(At),
             '!' => {
                 let r#match = self.r#match('=');
                 self.add_token(if r#match { BangEq } else { Bang })
             }
             '=' => {
-                let r#match = self.r#match('=');
-                self.add_token(if r#match { EqEq } else { Eq })
+                if self.r#match('=') {
+                    self.add_token(EqEq)
+                } else if self.r#match('>') {
+                    self.add_token(FatArrow)
+                } else {
+                    self.add_token(Eq)
+                }
             }
             '<' => {
                 let r#match = self.r#match('=');
@@ -85,6 +95,8 @@ impl<'a> Scanner<'a> {
                 let r#match = self.r#match('=');
                 self.add_token(if r#match { GreaterEq } else { Greater })
             }
+            '|' if self.r#match('>') => self.add_token(PipeGreater),
+            '#' if self.r#match('{') => self.add_token(HashBrace),
             '/' => {
                 if self.r#match('/') {
                     while !check_peek(self.peek(), '\n') && !self.is_at_end() {
@@ -98,8 +110,12 @@ impl<'a> Scanner<'a> {
             }
             ' ' | '\r' | '\t' => (),
             '\n' => self.line += 1,
-            '"' => self.string()?,
-            d if d.is_ascii_digit() => self.number(),
+            '"' => self.string(false)?,
+            'r' if check_peek(self.peek(), '"') => {
+                self.advance();
+                self.string(true)?
+            }
+            d if d.is_ascii_digit() => self.number(d)?,
             i if is_alpha(i) => self.identifier(),
             ch => {
                 return Err(ScanError {
@@ -131,6 +147,7 @@ impl<'a> Scanner<'a> {
             text.to_string(),
             literal,
             self.line,
+            self.start,
             self.current,
         ));
     }
@@ -153,76 +170,179 @@ impl<'a> Scanner<'a> {
         self.source.chars().nth(self.current)
     }
 
-    fn string(&mut self) -> Result<(), Error> {
-        let mut string = String::new();
-        while let Some(ch) = self.peek() {
-            string.push(match ch {
-                '"' => break,
-                '\n' => {
+    fn check_ahead(&self, expected: &str) -> bool {
+        self.source[self.bytes_cur..].starts_with(expected)
+    }
+
+    /// Scans a string literal after its opening quote. Triple-quoted literals
+    /// may span several lines and have their common indentation stripped, raw
+    /// literals keep backslashes as is.
+    fn string(&mut self, raw: bool) -> Result<(), Error> {
+        let mut first_line = self.line;
+        let text = if self.check_ahead("\"\"") {
+            self.advance();
+            self.advance();
+            let (text, skipped) = strip_indent(&self.block_string_body(raw)?);
+            first_line += skipped;
+            text
+        } else {
+            self.line_string_body(raw)?
+        };
+
+        let string = if raw {
+            text
+        } else {
+            unescape(&text, first_line)?
+        };
+        self.add_token_literal(TokenType::String, Value::String(intern(&string)));
+
+        Ok(())
+    }
+
+    fn line_string_body(&mut self, raw: bool) -> Result<String, Error> {
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => {
                     return Err(ScanError {
                         line: self.line,
                         msg: "Unterminated string".into(),
                     }
                     .into())
                 }
-                '\\' => {
+                Some('"') => {
                     self.advance();
-                    if !self.is_at_end() {
-                        match self.advance() {
-                            '0' => '\0',
-                            't' => '\t',
-                            'n' => '\n',
-                            'r' => '\r',
-                            '\\' => '\\',
-                            '"' => '"',
-                            ch => {
-                                return Err(ScanError {
-                                    line: self.line,
-                                    msg: format!("Unsupported escape symbol '\\{ch}'"),
-                                }
-                                .into())
-                            }
-                        }
-                    } else {
-                        break;
+                    break;
+                }
+                Some('\\') if !raw => {
+                    text.push(self.advance());
+                    if !check_peek(self.peek(), '\n') && !self.is_at_end() {
+                        text.push(self.advance());
                     }
                 }
-                _ => self.advance(),
-            })
+                Some(_) => text.push(self.advance()),
+            }
         }
 
-        if self.is_at_end() {
-            return Err(ScanError {
-                line: self.line,
-                msg: "Unterminated string".into(),
+        Ok(text)
+    }
+
+    fn block_string_body(&mut self, raw: bool) -> Result<String, Error> {
+        let start_line = self.line;
+        let mut text = String::new();
+        loop {
+            if self.check_ahead("\"\"\"") {
+                self.advance();
+                self.advance();
+                self.advance();
+                break;
+            }
+
+            match self.peek() {
+                None => {
+                    return Err(ScanError {
+                        line: start_line,
+                        msg: "Unterminated string".into(),
+                    }
+                    .into())
+                }
+                Some('\\') if !raw => {
+                    text.push(self.advance());
+                    if !self.is_at_end() {
+                        text.push(self.advance());
+                    }
+                }
+                Some(_) => text.push(self.advance()),
+            }
+
+            if text.ends_with('\n') {
+                self.line += 1;
             }
-            .into());
         }
 
-        self.advance();
+        Ok(text)
+    }
+
+    fn number(&mut self, first: char) -> Result<(), Error> {
+        let radix = match (first, self.peek()) {
+            ('0', Some('x' | 'X')) => Some((16, "hexadecimal")),
+            ('0', Some('o' | 'O')) => Some((8, "octal")),
+            ('0', Some('b' | 'B')) => Some((2, "binary")),
+            _ => None,
+        };
+
+        let num = match radix {
+            Some((radix, name)) => {
+                let prefix = self.advance();
+                let digits = self.digits(radix, None)?;
+                if digits.is_empty() {
+                    return Err(self.number_err(format!("Expected digits after '0{prefix}'")));
+                }
+                if let Some(ch) = self.peek().filter(|ch| is_alpha_numeric(*ch)) {
+                    return Err(self.number_err(format!("Invalid digit '{ch}' in {name} literal")));
+                }
+
+                digits.chars().fold(0., |acc, d| {
+                    acc * radix as f64 + d.to_digit(radix).unwrap() as f64
+                })
+            }
+            None => {
+                let mut text = self.digits(10, Some(first))?;
+
+                if check_peek(self.peek(), '.') && is_digit(self.peek_next()) {
+                    self.advance();
+                    text.push('.');
+                    text += &self.digits(10, None)?;
+                }
 
-        self.add_token_literal(TokenType::String, Value::String(string));
+                if check_peek_with(self.peek(), |ch| ch == 'e' || ch == 'E') {
+                    text.push(self.advance());
+                    if check_peek_with(self.peek(), |ch| ch == '+' || ch == '-') {
+                        text.push(self.advance());
+                    }
 
+                    let exponent = self.digits(10, None)?;
+                    if exponent.is_empty() {
+                        return Err(self.number_err("Expected digits in exponent".into()));
+                    }
+                    text += &exponent;
+                }
+
+                text.parse::<f64>().unwrap()
+            }
+        };
+
+        self.add_token_literal(TokenType::Number, Value::Number(num));
         Ok(())
     }
 
-    fn number(&mut self) {
-        while is_digit(self.peek()) {
-            self.advance();
-        }
+    /// Consumes a run of digits in the given radix, skipping `_` separators.
+    /// Returns the digits without separators.
+    fn digits(&mut self, radix: u32, first: Option<char>) -> Result<String, Error> {
+        let mut digits = String::from_iter(first);
 
-        if check_peek(self.peek(), '.') && is_digit(self.peek_next()) {
-            self.advance();
-            while is_digit(self.peek()) {
+        while let Some(ch) = self.peek() {
+            if ch.is_digit(radix) {
+                digits.push(self.advance());
+            } else if ch == '_' {
                 self.advance();
+                if digits.is_empty() || !check_peek_with(self.peek(), |ch| ch.is_digit(radix)) {
+                    return Err(self.number_err("Separator '_' must be between digits".into()));
+                }
+            } else {
+                break;
             }
         }
 
-        let num = self.source[self.bytes_start..self.bytes_cur]
-            .parse::<f64>()
-            .unwrap();
+        Ok(digits)
+    }
 
-        self.add_token_literal(TokenType::Number, Value::Number(num));
+    fn number_err(&self, msg: String) -> Error {
+        ScanError {
+            line: self.line,
+            msg: format!("Invalid number literal: {msg}"),
+        }
+        .into()
     }
 
     fn peek_next(&self) -> Option<char> {
@@ -234,13 +354,22 @@ impl<'a> Scanner<'a> {
             self.advance();
         }
 
-        let text = &self.source[self.bytes_start..self.bytes_cur];
-        let r#type = match KEYWORDS.get(text) {
+        // Identifiers are compared by their NFC form, so the lexeme is stored
+        // normalized and differently encoded names resolve to one variable
+        let text = unicode::to_nfc(&self.source[self.bytes_start..self.bytes_cur]);
+        let r#type = match KEYWORDS.get(text.as_str()) {
             None => TokenType::Identifier,
             Some(r#type) => *r#type,
         };
 
-        self.add_token(r#type);
+        self.tokens.push(Token::new(
+            r#type,
+            text,
+            Value::Nil,
+            self.line,
+            self.start,
+            self.current,
+        ));
     }
 
     fn comment(&mut self) {
@@ -266,12 +395,117 @@ impl<'a> Scanner<'a> {
     }
 }
 
+/// Replaces the escapes in a string literal whose text starts on `line`
+fn unescape(text: &str, mut line: usize) -> Result<String, Error> {
+    let mut string = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            if ch == '\n' {
+                line += 1;
+            }
+            string.push(ch);
+            continue;
+        }
+
+        string.push(match chars.next() {
+            Some('0') => '\0',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('x') => {
+                let code: String = chars.by_ref().take(2).collect();
+                if code.len() != 2 || !code.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                    return Err(escape_err(line, "Expected two hex digits after '\\x'"));
+                }
+                char::from(u8::from_str_radix(&code, 16).unwrap())
+            }
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return Err(escape_err(line, "Expected '{' after '\\u'"));
+                }
+
+                let rest = chars.as_str();
+                let end = rest.find('}').unwrap_or(rest.len());
+                let code = &rest[..end];
+                chars = rest[(end + 1).min(rest.len())..].chars();
+                if end == rest.len()
+                    || code.is_empty()
+                    || code.len() > 6
+                    || !code.chars().all(|ch| ch.is_ascii_hexdigit())
+                {
+                    return Err(escape_err(line, "Expected 1 to 6 hex digits in '\\u{...}'"));
+                }
+
+                u32::from_str_radix(code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| {
+                        escape_err(line, &format!("Invalid unicode escape '\\u{{{code}}}'"))
+                    })?
+            }
+            Some(ch) => {
+                return Err(escape_err(
+                    line,
+                    &format!("Unsupported escape symbol '\\{ch}'"),
+                ))
+            }
+            None => return Err(escape_err(line, "Unterminated escape sequence")),
+        })
+    }
+
+    Ok(string)
+}
+
+fn escape_err(line: usize, msg: &str) -> Error {
+    ScanError {
+        line,
+        msg: msg.into(),
+    }
+    .into()
+}
+
+/// Removes the line break after the opening quotes, the whitespace-only line
+/// before the closing quotes and the indentation common to all non-blank lines.
+/// Also returns how many lines were dropped before the text.
+fn strip_indent(text: &str) -> (String, usize) {
+    let is_blank = |line: &str| line.trim_matches([' ', '\t', '\r']).is_empty();
+
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if lines.len() == 1 {
+        return (text.to_string(), 0);
+    }
+    let skipped = is_blank(lines[0]) as usize;
+    if skipped > 0 {
+        lines.remove(0);
+    }
+    if lines.len() > 1 && is_blank(lines[lines.len() - 1]) {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !is_blank(line))
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+
+    let text = lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (text, skipped)
+}
+
 fn is_alpha(c: char) -> bool {
-    ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) || c == '_'
+    unicode::is_xid_start(c) || c == '_'
 }
 
 fn is_alpha_numeric(c: char) -> bool {
-    is_alpha(c) || c.is_ascii_digit()
+    unicode::is_xid_continue(c)
 }
 
 fn is_digit(ch: Option<char>) -> bool {
@@ -0,0 +1,1103 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        ast::TokenAstInfo,
+        checker::Checker,
+        error::{Error, Limit, RuntimeError},
+        interner::{intern, interned},
+        interpreter::Interpreter,
+        parser::Parser,
+        resolver::Resolver,
+        scanner::Scanner,
+        token::Token,
+        tokentype::TokenType,
+        value::Value,
+    };
+
+    /// The same script run by the tree-walker and by the bytecode VM
+    struct Runs {
+        tree: Interpreter,
+        vm: Interpreter,
+    }
+
+    impl Runs {
+        fn take_fiber_errors(&mut self) -> Vec<Error> {
+            let errors = self.tree.take_fiber_errors();
+            let vm_errors = self.vm.take_fiber_errors();
+            assert_eq!(format!("{errors:?}"), format!("{vm_errors:?}"));
+            errors
+        }
+    }
+
+    fn run(source: &str) -> (Runs, Result<(), Error>) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let ast = Parser::new(source, tokens).parse().unwrap();
+        Checker::new().check(&ast).unwrap();
+
+        run_both(source, |_| ())
+    }
+
+    /// Runs the script with both backends and checks that they agree on the
+    /// result
+    fn run_both(source: &str, configure: impl Fn(&mut Interpreter)) -> (Runs, Result<(), Error>) {
+        let run_with = |bytecode| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let ast = Parser::new(source, tokens).parse().unwrap();
+
+            let mut interpreter = Interpreter::new();
+            configure(&mut interpreter);
+            interpreter.set_bytecode(bytecode);
+            Resolver::new().resolve(&ast).unwrap();
+            let res = interpreter.interpret(ast);
+
+            (interpreter, res)
+        };
+
+        let (tree, res) = run_with(false);
+        let (vm, vm_res) = run_with(true);
+        assert_eq!(format!("{res:?}"), format!("{vm_res:?}"));
+
+        (Runs { tree, vm }, res)
+    }
+
+    fn resolve_errors(source: &str) -> Vec<String> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let ast = Parser::new(source, tokens).parse().unwrap();
+
+        match Resolver::new().resolve(&ast) {
+            Ok(_) => vec![],
+            Err(errors) => errors
+                .into_iter()
+                .map(|e| match e {
+                    Error::ParseError(pe) => pe.msg,
+                    e => panic!("Expected resolver error, got {e:?}"),
+                })
+                .collect(),
+        }
+    }
+
+    fn type_errors(source: &str) -> Vec<String> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let ast = Parser::new(source, tokens).parse().unwrap();
+
+        match Checker::new().check(&ast) {
+            Ok(_) => vec![],
+            Err(errors) => errors
+                .into_iter()
+                .map(|e| match e {
+                    Error::ParseError(pe) => pe.msg,
+                    e => panic!("Expected type error, got {e:?}"),
+                })
+                .collect(),
+        }
+    }
+
+    fn global(runs: &Runs, name: &str) -> Value {
+        let token = Token::new(TokenType::Identifier, name.into(), Value::Nil, 0, 0, 0);
+        let get = |interpreter: &Interpreter| {
+            interpreter
+                .globals
+                .borrow()
+                .get(&TokenAstInfo::from(&token))
+                .unwrap()
+        };
+
+        let val = get(&runs.tree);
+        assert_eq!(val.to_string(), get(&runs.vm).to_string());
+        val
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn defer_runs_in_reverse_order_on_block_exit() {
+        let (interpreter, res) = run(r#"
+            var log = "";
+            var push = fun (s) { log = log + s; };
+            {
+                defer push("a");
+                defer push("b");
+                push("c");
+            }
+            push("d");
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "log"), string("cbad"));
+    }
+
+    #[test]
+    fn defer_runs_on_return() {
+        let (interpreter, res) = run(r#"
+            var log = "";
+            var push = fun (s) { log = log + s; };
+            fun f() {
+                defer push("deferred");
+                push("body ");
+                return 1;
+            }
+            var result = f();
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "log"), string("body deferred"));
+        assert_eq!(global(&interpreter, "result"), Value::Number(1.));
+    }
+
+    #[test]
+    fn defer_runs_on_runtime_error() {
+        let (interpreter, res) = run(r#"
+            var log = "";
+            var push = fun (s) { log = log + s; };
+            fun f() {
+                defer push("cleanup");
+                var x = 1 + nil;
+            }
+            f();
+        "#);
+
+        assert!(matches!(res, Err(Error::RuntimeError(_))));
+        assert_eq!(global(&interpreter, "log"), string("cleanup"));
+    }
+
+    #[test]
+    fn deferred_errors_replace_loop_exits() {
+        for exit in ["break", "continue"] {
+            let source = format!(
+                "var runs = 0;
+                while (runs < 2) {{ runs = runs + 1; defer 1 + nil; {exit}; }}"
+            );
+            let (runs, res) = run_both(&source, |_| ());
+
+            assert!(
+                matches!(res, Err(Error::RuntimeError(_))),
+                "{exit}: {res:?}"
+            );
+            assert_eq!(global(&runs, "runs"), Value::Number(1.));
+        }
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let (runs, res) = run(r#"
+            fun counter() {
+                var n = 0;
+                fun inc() { n = n + 1; return n; }
+                var get = fun () => n;
+                return (inc, get);
+            }
+            var c = counter();
+            c.0();
+            c.0();
+            var count = c.1();
+
+            var parts = "";
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = i;
+                var f = fun () => j * 10;
+                parts = parts + str(f()) + " ";
+            }
+
+            var sum;
+            {
+                fun down(n) { if (n == 0) return 0; return n + down(n - 1); }
+                sum = down(10);
+            }
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&runs, "count"), Value::Number(2.));
+        assert_eq!(global(&runs, "parts"), string("0 10 20 "));
+        assert_eq!(global(&runs, "sum"), Value::Number(55.));
+    }
+
+    #[test]
+    fn cyclic_closures_are_reclaimed() {
+        let (runs, res) = run(r#"
+            fun make() {
+                fun rec(n) { if (n > 0) return rec(n - 1); return n; }
+                var s;
+                s = fun () => s;
+                return rec(3);
+            }
+            for (var i = 0; i < 10; i = i + 1) make();
+            var first = gc().freed >= 10;
+            var second = gc().freed;
+
+            fun lambda() {
+                var s;
+                s = fun () => s;
+            }
+            for (var i = 0; i < 10; i = i + 1) lambda();
+            var lambdas = gc().freed >= 10;
+
+            var keep;
+            {
+                fun countdown(n) { if (n == 0) return "alive"; return countdown(n - 1); }
+                keep = countdown;
+            }
+            var kept = gc().freed;
+            var result = keep(3);
+
+            var automatic;
+            {
+                var before = gc().collections;
+                for (var i = 0; i < 3000; i = i + 1) make();
+                automatic = gc().collections > before + 1;
+            }
+        "#);
+
+        res.unwrap();
+        for interpreter in [&runs.tree, &runs.vm] {
+            let stats = interpreter.collector().borrow().stats();
+            assert!(stats.total_freed >= 3010, "{stats:?}");
+        }
+        assert_eq!(global(&runs, "first"), Value::Bool(true));
+        assert_eq!(global(&runs, "second"), Value::Number(0.));
+        assert_eq!(global(&runs, "lambdas"), Value::Bool(true));
+        assert_eq!(global(&runs, "kept"), Value::Number(0.));
+        assert_eq!(global(&runs, "result"), string("alive"));
+        assert_eq!(global(&runs, "automatic"), Value::Bool(true));
+    }
+
+    #[test]
+    fn strings_are_shared() {
+        let (runs, res) = run(r#"
+            var a = "shared";
+            var b = "shared";
+            var c = "sha" + "red";
+            var record = { shared: 1 };
+            var has_field = a in record;
+        "#);
+
+        res.unwrap();
+        let (Value::String(a), Value::String(b), Value::String(c)) =
+            (global(&runs, "a"), global(&runs, "b"), global(&runs, "c"))
+        else {
+            panic!("Expected strings");
+        };
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(a, c);
+        assert_eq!(global(&runs, "has_field"), Value::Bool(true));
+    }
+
+    #[test]
+    fn unused_strings_are_evicted() {
+        for i in 0..10_000 {
+            intern(&format!("temporary {i}"));
+        }
+        let kept = intern("kept");
+        for i in 0..10_000 {
+            intern(&format!("temporary {i}"));
+        }
+
+        assert!(interned() < 5000);
+        assert!(Rc::ptr_eq(&kept, &intern("kept")));
+    }
+
+    #[test]
+    fn locals_resolve_to_their_slots() {
+        let (runs, res) = run(r#"
+            var shadowed;
+            var nested;
+            {
+                var a = 1;
+                var b = 2;
+                {
+                    // The initializer still sees the outer `a`
+                    var a = a + 10;
+                    var c = b * a;
+                    b = c;
+                    shadowed = a;
+                }
+                nested = (a, b);
+            }
+
+            fun f(x, y) ensures result == x - y {
+                var d = x - y;
+                return d;
+            }
+            var diff = f(7, 2);
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&runs, "shadowed"), Value::Number(11.));
+        assert_eq!(global(&runs, "nested").to_string(), "(1, 22)");
+        assert_eq!(global(&runs, "diff"), Value::Number(5.));
+    }
+
+    #[test]
+    fn do_while_runs_body_first() {
+        let (interpreter, res) = run(r#"
+            var i = 0;
+            do i = i + 1; while (i < 0);
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "i"), Value::Number(1.));
+    }
+
+    #[test]
+    fn continue_in_for_runs_increment() {
+        let (interpreter, res) = run(r#"
+            var sum = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                if (i == 2) continue;
+                sum = sum + i;
+            }
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "sum"), Value::Number(8.));
+    }
+
+    #[test]
+    fn labeled_loop_exits() {
+        let (interpreter, res) = run(r#"
+            var log = "";
+            outer: for (var a = 0; a < 3; a = a + 1) {
+                var b = 0;
+                loop {
+                    b = b + 1;
+                    if (b > 2) continue outer;
+                    if (a == 2) break outer;
+                    log = log + str(a) + str(b) + " ";
+                }
+            }
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "log"), string("01 02 11 12 "));
+    }
+
+    #[test]
+    fn invalid_loop_exits() {
+        assert_eq!(
+            resolve_errors(
+                r#"
+                break;
+                x: while (true) { x: loop { break; } }
+                while (true) { continue y; }
+                loop { var f = fun () { break; }; }
+                "#
+            ),
+            vec![
+                "Can't use 'break' outside of a loop",
+                "Label shadows a label of an enclosing loop",
+                "Undefined label 'y'",
+                "Can't use 'break' outside of a loop",
+            ]
+        );
+    }
+
+    #[test]
+    fn assert_reports_source_and_operands() {
+        let (_, res) = run(r#"
+            var a = 1;
+            fun f(x) { return x + 1; }
+            assert f(a) == -a * 3, "f of " + str(a);
+        "#);
+
+        match res {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(
+                    re.msg,
+                    "Assertion failed: f of 1 (f(a) == -a * 3)\n  left: 2\n  right: -3"
+                );
+                assert_eq!(re.token.line, 4);
+            }
+            res => panic!("Expected assertion failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn parenthesized_assert_reports_operands() {
+        let (_, res) = run("var a = 1; assert ((a == 2)), \"m\";");
+
+        match res {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(
+                    re.msg,
+                    "Assertion failed: m (((a == 2)))\n  left: 1\n  right: 2"
+                )
+            }
+            res => panic!("Expected assertion failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn default_interpreter_runs_calls_and_assertions() {
+        let source = "fun id(x) { return x; } var n = id(1); assert n == 2;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let ast = Parser::new(source, tokens).parse().unwrap();
+
+        let mut interpreter = Interpreter::default();
+        assert_eq!(
+            interpreter.max_call_depth(),
+            Interpreter::new().max_call_depth()
+        );
+        Resolver::new().resolve(&ast).unwrap();
+        match interpreter.interpret(ast) {
+            Err(Error::RuntimeError(re)) => assert!(re.msg.starts_with("Assertion failed")),
+            res => panic!("Expected assertion failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn assert_without_comparison() {
+        let (_, res) = run("var ok = false; assert !!ok;");
+
+        match res {
+            Err(Error::RuntimeError(re)) => assert_eq!(re.msg, "Assertion failed: !!ok"),
+            res => panic!("Expected assertion failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn assertions_quote_their_source() {
+        let (_, res) = run("var ok = false; assert !ok  and /* both */ (ok);");
+
+        match res {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.msg, "Assertion failed: !ok  and /* both */ (ok)")
+            }
+            res => panic!("Expected assertion failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn disabled_assertions_are_not_evaluated() {
+        let (runs, res) = run_both("var n = 0; assert (n = 1) == 2;", |interpreter| {
+            interpreter.set_assertions(false)
+        });
+
+        res.unwrap();
+        assert_eq!(global(&runs, "n"), Value::Number(0.));
+    }
+
+    #[test]
+    fn tuples_and_records() {
+        let (interpreter, res) = run(r#"
+            var t = (1, "a", (true, nil));
+            var p = { x: 1, y: (2, 3) };
+            var inner = t.2.0;
+            var y1 = p.y.1;
+            var same = p == { y: (2, 3), x: 1 };
+            var ordered = (1, 2) == (2, 1);
+            var text = str(t) + " " + str(p) + " " + str((1,));
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "inner"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "y1"), Value::Number(3.));
+        assert_eq!(global(&interpreter, "same"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "ordered"), Value::Bool(false));
+        assert_eq!(
+            global(&interpreter, "text"),
+            string("(1, a, (true, Nil)) {x: 1, y: (2, 3)} (1,)")
+        );
+    }
+
+    #[test]
+    fn missing_fields() {
+        for (source, msg) in [
+            ("(1, 2).2;", "Index 2 out of range for tuple of length 2"),
+            ("var r = { x: 1 }; r.y;", "Undefined field 'y'"),
+            ("var n = 1; n.x;", "Only tuples and records have fields"),
+        ] {
+            match run(source).1 {
+                Err(Error::RuntimeError(re)) => assert_eq!(re.msg, msg),
+                res => panic!("Expected runtime error for {source}, got {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn set_membership_and_algebra() {
+        let (interpreter, res) = run(r#"
+            var nan = 0 / 0;
+            var s = #{3, 1, 2, 1, -0, nan};
+            var t = #{2, 3, 4, (1, "a")};
+            var text = str(s);
+            var has_zero = 0 in s;
+            var has_nan = nan in s;
+            var has_tuple = (1, "a") in t;
+            var u = str(union(s, t));
+            var i = str(intersection(s, t));
+            var d = str(difference(s, t));
+            var same = #{1, #{2}} == #{#{2}, 1};
+            var nested = str(#{(nan,), (0 / 0,), {x: (nan, 1)}, {x: (nan, 1)}});
+            var has_nested = (0 / 0,) in #{(nan,)};
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "text"), string("#{3, 1, 2, -0, NaN}"));
+        assert_eq!(global(&interpreter, "has_zero"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "has_nan"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "has_tuple"), Value::Bool(true));
+        assert_eq!(
+            global(&interpreter, "u"),
+            string("#{3, 1, 2, -0, NaN, 4, (1, a)}")
+        );
+        assert_eq!(global(&interpreter, "i"), string("#{3, 2}"));
+        assert_eq!(global(&interpreter, "d"), string("#{1, -0, NaN}"));
+        assert_eq!(global(&interpreter, "same"), Value::Bool(true));
+        assert_eq!(
+            global(&interpreter, "nested"),
+            string("#{(NaN,), {x: (NaN, 1)}}")
+        );
+        assert_eq!(global(&interpreter, "has_nested"), Value::Bool(true));
+    }
+
+    #[test]
+    fn functions_are_not_set_elements() {
+        match run("var s = #{1, (2, clock)};").1 {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.msg, "(2, <native fun>) can't be a set element")
+            }
+            res => panic!("Expected runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn annotated_code_runs() {
+        let (interpreter, res) = run(r#"
+            var x: number = 1;
+            fun same(a: string, b): bool { return a == b; }
+            var double = fun (n: number): number => n * 2;
+            var r = same("a", double(x));
+            var untyped = "s" + "t";
+            "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "r"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "untyped"), string("st"));
+    }
+
+    #[test]
+    fn type_errors_are_reported() {
+        assert_eq!(
+            type_errors(
+                r#"
+                var x: number = "one";
+                fun f(a: string): bool { return 1; }
+                f(1);
+                f("a", 2);
+                var y: string = 1 + 2;
+                var n: number = 0;
+                n = "s";
+                var s: string = "s";
+                var z = -s;
+                var ok = -"s" + nil;
+                var w: nil = nil;
+                var any = clock;
+                any(1, 2) + 1;
+                fun g() {}
+                if (false) print g < 1;
+                if (false) print -g + (1, 2) - {a: 1} * #{1};
+                "#
+            ),
+            vec![
+                "Type error: Expected number but got string",
+                "Type error: Expected bool but got number",
+                "Type error: Expected string for argument 1 but got number",
+                "Type error: Expected 1 arguments but got 2",
+                "Type error: Expected string but got number",
+                "Type error: Expected number but got string",
+                "Type error: Operand must be a number, got string",
+            ]
+        );
+    }
+
+    #[test]
+    fn unannotated_scripts_pass_the_checker() {
+        let (runs, res) = run(r#"
+            fun f() {}
+            f = 2;
+
+            fun h(a) { return a; }
+            h = fun (a, b) => a + b;
+            var sum = h(1, 2);
+
+            fun early(a) { return a; }
+            fun g() { return early(1, 2); }
+            fun early(a, b) { return a + b; }
+            var redefined = g();
+
+            fun once(a) {}
+            if (false) once(1, 2);
+            if (false) print clock() - "s";
+            var after = "ran";
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&runs, "f"), Value::Number(2.));
+        assert_eq!(global(&runs, "sum"), Value::Number(3.));
+        assert_eq!(global(&runs, "redefined"), Value::Number(3.));
+        assert_eq!(global(&runs, "after"), string("ran"));
+    }
+
+    #[test]
+    fn tail_calls_dont_grow_the_stack() {
+        let (runs, res) = run(r#"
+            fun spin(n) { if (n == 0) return "done"; return spin(n - 1); }
+            var spun = spin(100000);
+
+            var is_odd;
+            fun is_even(n) { if (n == 0) return true; return (is_odd(n - 1)); }
+            is_odd = fun (n) => n != 0 and is_even(n - 1);
+            var even = is_even(100001);
+
+            var log = "";
+            fun push(s) { log = log + s; }
+            fun deferring() { defer push("deferred "); return push("called "); }
+            deferring();
+        "#);
+
+        res.unwrap();
+        assert_eq!(global(&runs, "spun"), string("done"));
+        assert_eq!(global(&runs, "even"), Value::Bool(false));
+        assert_eq!(global(&runs, "log"), string("called deferred "));
+    }
+
+    #[test]
+    fn deep_recursion_overflows_the_stack() {
+        let source = r#"
+            fun d(n) { if (n == 0) return 0; return 1 + d(n - 1); }
+            fun spin(n) { if (n == 0) return "done"; return spin(n - 1); }
+            var fits = d(19);
+            var spun = spin(1000);
+            d(20);
+        "#;
+        let (runs, res) = run_both(source, |interpreter| interpreter.set_max_call_depth(20));
+
+        assert_eq!(global(&runs, "fits"), Value::Number(19.));
+        assert_eq!(global(&runs, "spun"), string("done"));
+        match res {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.msg, "Stack overflow");
+                assert_eq!(re.token.line, 2);
+            }
+            res => panic!("Expected runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn step_budget_and_deadline_stop_scripts() {
+        let endless = "var i = 0; while (true) i = i + 1;";
+        let (runs, res) = run_both(endless, |interpreter| {
+            interpreter.set_step_budget(Some(1000))
+        });
+        assert!(matches!(res, Err(Error::LimitExceeded(Limit::Steps))));
+        assert_eq!(runs.tree.step_budget(), Some(0));
+        assert_eq!(runs.vm.step_budget(), Some(0));
+
+        let (runs, res) = run_both("var n = 1 + 2;", |interpreter| {
+            interpreter.set_step_budget(Some(1000))
+        });
+        res.unwrap();
+        assert!(runs.tree.step_budget() > Some(990));
+        assert!(runs.vm.step_budget() > Some(990));
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let (_, res) = run_both(endless, |interpreter| {
+            interpreter.set_deadline(Some(deadline))
+        });
+        assert!(matches!(res, Err(Error::LimitExceeded(Limit::Deadline))));
+
+        for waiting in ["await sleep(3000);", "set_timeout(fun () {}, 3000);"] {
+            let started = Instant::now();
+            let deadline = started + Duration::from_millis(50);
+            let (_, res) = run_both(waiting, |interpreter| {
+                interpreter.set_deadline(Some(deadline))
+            });
+            assert!(matches!(res, Err(Error::LimitExceeded(Limit::Deadline))));
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn memory_is_accounted_and_limited() {
+        let (runs, res) = run(r#"
+            var s = "x";
+            for (var i = 0; i < 20; i = i + 1) s = s + s;
+            var grown = memory().current > 1000000;
+            s = nil;
+            var usage = memory();
+            var freed = usage.current < 1000000 and usage.peak > 1500000;
+        "#);
+        res.unwrap();
+        assert_eq!(global(&runs, "grown"), Value::Bool(true));
+        assert_eq!(global(&runs, "freed"), Value::Bool(true));
+
+        let (runs, res) = run_both("var s = \"x\"; while (true) s = s + s;", |interpreter| {
+            interpreter.set_memory_limit(Some(1_000_000))
+        });
+        assert!(matches!(res, Err(Error::LimitExceeded(Limit::Memory))));
+        assert!(runs.tree.memory().peak > 1_000_000);
+        assert!(runs.vm.memory().peak > 1_000_000);
+    }
+
+    #[test]
+    fn checked_results_are_not_tail_calls() {
+        let err = type_hint_error(
+            r#"
+            fun g() { return "one"; }
+            fun f(): number { return g(); }
+            f();
+            "#,
+        );
+        assert_eq!(err.msg, "Expected number as return value but got string");
+        assert_eq!(err.token.line, 4);
+
+        match run("fun g(n) { return n; } fun f(n) ensures result > 0 { return g(n); } f(0);").1 {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.msg, "Postcondition of 'f' failed: result > 0")
+            }
+            res => panic!("Expected runtime error, got {res:?}"),
+        }
+    }
+
+    fn type_hint_error(source: &str) -> RuntimeError {
+        match run_both(source, |interpreter| interpreter.set_type_hints(true)).1 {
+            Err(Error::RuntimeError(re)) => re,
+            res => panic!("Expected runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn type_hints_are_checked_on_call() {
+        let err = type_hint_error(
+            r#"
+            var s = "one";
+            fun f(a, b: number) { return a; }
+            f(1, 2);
+            f(s,
+              s);
+            "#,
+        );
+        assert_eq!(err.msg, "Expected number for parameter 'b' but got string");
+        assert_eq!((err.token.kind, err.token.line), (TokenType::RightParen, 6));
+
+        let err = type_hint_error(
+            r#"
+            var s = fun () => "one";
+            fun f(): number { return s(); }
+            f();
+            "#,
+        );
+        assert_eq!(err.msg, "Expected number as return value but got string");
+        assert_eq!(err.token.line, 4);
+
+        let err = type_hint_error("var f = fun (g: fun) => g; var n = 1; f(clock); f(n);");
+        assert_eq!(err.msg, "Expected fun for parameter 'g' but got number");
+
+        let (interpreter, res) = run(r#"
+            var s = "one";
+            fun f(a: number): number { return a; }
+            var r = f(s);
+            "#);
+        res.unwrap();
+        assert_eq!(global(&interpreter, "r"), string("one"));
+    }
+
+    #[test]
+    fn fibers_interleave_on_yield_and_receive() {
+        let (mut interpreter, res) = run(r#"
+            var ch = channel();
+            var log = "";
+            spawn(fun () {
+                for (var i = 0; i < 3; i = i + 1) {
+                    log = log + "p" + str(i) + " ";
+                    send(ch, i);
+                    yield_now();
+                }
+                send(ch, nil);
+            });
+            spawn(fun () {
+                var v = receive(ch);
+                while (v != nil) {
+                    log = log + "c" + str(v) + " ";
+                    v = receive(ch);
+                }
+            });
+            log = log + "main ";
+            "#);
+
+        res.unwrap();
+        assert!(interpreter.take_fiber_errors().is_empty());
+        assert_eq!(
+            global(&interpreter, "log"),
+            string("main p0 c0 p1 c1 p2 c2 ")
+        );
+    }
+
+    #[test]
+    fn fiber_errors_do_not_stop_other_fibers() {
+        let (mut interpreter, res) = run(r#"
+            var done = false;
+            spawn(fun () { yield_now(); var x = 1 + nil; });
+            spawn(fun () { yield_now(); yield_now(); done = true; });
+            "#);
+
+        res.unwrap();
+        let errors = interpreter.take_fiber_errors();
+        assert!(matches!(&errors[..], [Error::RuntimeError(re)] if re.token.line == 3));
+        assert_eq!(global(&interpreter, "done"), Value::Bool(true));
+    }
+
+    #[test]
+    fn fibers_fail_to_start_once_too_many_are_running() {
+        let (mut interpreter, res) = run(r#"
+            var ran = 0;
+            for (var i = 0; i < 2000; i = i + 1) spawn(fun () { yield_now(); ran = ran + 1; });
+            "#);
+
+        res.unwrap();
+        let errors = interpreter.take_fiber_errors();
+        assert_eq!(errors.len(), 977);
+        assert!(errors.iter().all(|e| matches!(e, Error::RuntimeError(re)
+            if re.msg == "Too many fibers, at most 1024 can be running at once"
+                && re.token.line == 3)));
+        // The others still run, each on a thread of its own
+        assert_eq!(global(&interpreter, "ran"), Value::Number(1023.));
+    }
+
+    #[test]
+    fn async_calls_share_threads() {
+        let (mut interpreter, res) = run(r#"
+            async fun id(i) { return i; }
+            var last;
+            for (var i = 0; i < 20000; i = i + 1) last = id(i);
+            var sum = await last;
+            for (var i = 0; i < 2000; i = i + 1) sum = sum + await id(i);
+            "#);
+
+        res.unwrap();
+        assert!(interpreter.take_fiber_errors().is_empty());
+        assert_eq!(
+            global(&interpreter, "sum"),
+            Value::Number(19999. + 1999000.)
+        );
+    }
+
+    #[test]
+    fn receive_detects_deadlock() {
+        let (mut interpreter, res) = run(r#"
+            var ch = channel();
+            spawn(fun () { receive(ch); });
+            receive(ch);
+            "#);
+
+        match res {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.msg, "Deadlock: every fiber is waiting on a channel");
+                assert_eq!(re.token.line, 4);
+            }
+            res => panic!("Expected deadlock, got {res:?}"),
+        }
+        assert_eq!(interpreter.take_fiber_errors().len(), 1);
+    }
+
+    #[test]
+    fn timers_and_async_functions() {
+        let (mut interpreter, res) = run(r#"
+            var log = "";
+            async fun work(name, ms) {
+                await sleep(ms);
+                log = log + name + " ";
+                return name;
+            }
+            set_timeout(fun () { log = log + "timer "; }, 10);
+            var slow = work("slow", 30);
+            var fast = work("fast", 1);
+            log = log + "main ";
+            var result = await slow;
+            set_timeout(fun () { log = log + "after "; }, 0);
+            "#);
+
+        res.unwrap();
+        assert!(interpreter.take_fiber_errors().is_empty());
+        assert_eq!(global(&interpreter, "result"), string("slow"));
+        assert_eq!(
+            global(&interpreter, "log"),
+            string("main fast timer slow after ")
+        );
+    }
+
+    #[test]
+    fn too_long_delays_are_rejected() {
+        for source in ["set_timeout(fun () {}, 1e300);", "sleep(1/0);"] {
+            match run(source).1 {
+                Err(Error::NativeCallError(msg)) => assert_eq!(msg, "Delay is too long"),
+                res => panic!("Expected native call error, got {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unhandled_rejections_are_reported() {
+        let (mut interpreter, res) = run(r#"
+            async fun fail() { return 1 + nil; }
+            var unhandled = fail();
+            var handled = fail();
+            async fun check() {
+                await handled;
+            }
+            var checked = check();
+            "#);
+
+        res.unwrap();
+        let errors = interpreter.take_fiber_errors();
+        let msgs: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                Error::RuntimeError(re) => (re.msg.as_str(), re.token.line),
+                e => panic!("Expected runtime error, got {e:?}"),
+            })
+            .collect();
+        // `handled` rejects `checked` in turn, which is never awaited
+        assert_eq!(
+            msgs,
+            vec![
+                (
+                    "Unhandled promise rejection: Operands must be either two strings or two numbers",
+                    2
+                ),
+                (
+                    "Unhandled promise rejection: Operands must be either two strings or two numbers",
+                    2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn await_outside_async_function() {
+        assert_eq!(
+            resolve_errors(
+                r#"
+                await sleep(1);
+                async fun f() { await sleep(1); }
+                fun g() { await sleep(1); }
+                var h = async fun () { var i = fun () => await 1; };
+                "#
+            ),
+            vec![
+                "Can't use 'await' outside of an async function",
+                "Can't use 'await' outside of an async function",
+            ]
+        );
+    }
+
+    #[test]
+    fn decorators_wrap_functions() {
+        let (interpreter, res) = run(r#"
+            var log = "";
+            fun trace(f) {
+                return fun (n) {
+                    log = log + str(n) + " ";
+                    return f(n);
+                };
+            }
+            fun scale(k) {
+                return fun (f) => fun (n) => f(n) * k;
+            }
+
+            @trace
+            @scale(10)
+            fun id(n) { return n; }
+            var scaled = id(2);
+
+            // Recursive calls go through the decorated function
+            @trace
+            fun count(n) { if (n > 0) count(n - 1); return n; }
+            count(2);
+
+            var local;
+            {
+                fun double(f) { return fun (n) => f(n) * 2; }
+                @double
+                fun one(n) { return n; }
+                local = one(1);
+            }
+            "#);
+
+        res.unwrap();
+        assert_eq!(global(&interpreter, "scaled"), Value::Number(20.));
+        assert_eq!(global(&interpreter, "log"), string("2 2 1 0 "));
+        assert_eq!(global(&interpreter, "local"), Value::Number(2.));
+    }
+
+    #[test]
+    fn decorator_errors_point_at_decorator() {
+        for source in [
+            "var x = 1;\n\n@x\nfun f() {}",
+            "fun f() {}\n\n@num\nfun g() {}",
+        ] {
+            match run(source).1 {
+                Err(Error::RuntimeError(re)) => assert_eq!(re.token.line, 3),
+                res => panic!("Expected runtime error, got {res:?}"),
+            }
+        }
+
+        let source = "fun bad(f) { return 1 + nil; }\n\n@bad\nfun g() {}";
+        match run_both(source, |_| ()).1 {
+            Err(Error::RuntimeError(re)) => {
+                assert_eq!(re.token.line, 1);
+                assert!(re.msg.ends_with(" in decorator at line 3"), "{}", re.msg);
+            }
+            res => panic!("Expected runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn contracts_are_checked_on_call() {
+        let (interpreter, res) = run(r#"
+            fun abs(x) requires x != nil ensures result >= 0 ensures result == x or result == -x {
+                if (x < 0) return -x;
+                return x;
+            }
+            var a = abs(-3);
+            "#);
+        res.unwrap();
+        assert_eq!(global(&interpreter, "a"), Value::Number(3.));
+
+        let violation = |source| match run(source).1 {
+            Err(Error::RuntimeError(re)) => (re.msg, re.token.line),
+            res => panic!("Expected contract violation, got {res:?}"),
+        };
+        assert_eq!(
+            violation("fun f(x) requires x > 0 { return x; }\nf(0);"),
+            ("Precondition of 'f' failed: x > 0".into(), 2)
+        );
+        assert_eq!(
+            violation("fun f(x)\nensures result > x { return x; }\nf(0);"),
+            ("Postcondition of 'f' failed: result > x".into(), 2)
+        );
+        // Locals of the body are out of scope in postconditions
+        assert_eq!(
+            violation("fun f() ensures y == 1 { var y = 1; return y; }\nf();"),
+            ("Undefined variable 'y'".into(), 1)
+        );
+    }
+
+    #[test]
+    fn disabled_assertions_skip_contracts() {
+        let (runs, res) = run_both(
+            "fun f(x) requires x > 0 ensures false { return x; } var r = f(0);",
+            |interpreter| interpreter.set_assertions(false),
+        );
+
+        res.unwrap();
+        assert_eq!(global(&runs, "r"), Value::Number(0.));
+    }
+}
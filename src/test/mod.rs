@@ -1,2 +1,4 @@
+mod interpreter;
 mod parser;
 mod scanner;
+mod vm;
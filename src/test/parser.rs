@@ -40,3 +40,240 @@
 //         assert_eq!(repr, "(+ (- (+ 1.0 (* 2.0 3.0)) (/ 4.0 5.0)) 6.0)");
 //     }
 // }
+
+#[cfg(test)]
+mod pipeline_tests {
+    use crate::{
+        ast::{stmt::Stmt, Expr},
+        parser::Parser,
+        scanner::Scanner,
+        tokentype::TokenType,
+        value::Value,
+    };
+
+    fn parse_expr(source: &str) -> Expr {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let mut stmts = Parser::new(source, tokens).parse().unwrap();
+        match stmts.remove(0) {
+            Stmt::Expr(expr) => expr,
+            stmt => panic!("Expected expression statement, got {stmt:?}"),
+        }
+    }
+
+    fn callee_name(expr: &Expr) -> &str {
+        match expr {
+            Expr::Variable { name, .. } => name.get_name(),
+            expr => panic!("Expected variable, got {expr:?}"),
+        }
+    }
+
+    #[test]
+    fn inserts_first_argument() {
+        let Expr::Call {
+            callee,
+            paren,
+            args,
+            ..
+        } = parse_expr("x |> f(a, b);")
+        else {
+            panic!("Expected call");
+        };
+
+        assert_eq!(callee_name(&callee), "f");
+        assert_eq!(paren.kind, TokenType::RightParen);
+        let args: Vec<&str> = args.iter().map(callee_name).collect();
+        assert_eq!(args, vec!["x", "a", "b"]);
+    }
+
+    #[test]
+    fn bare_callee_is_chained() {
+        let Expr::Call {
+            callee,
+            paren,
+            args,
+            ..
+        } = parse_expr("x |> f |> g;")
+        else {
+            panic!("Expected call");
+        };
+
+        assert_eq!(callee_name(&callee), "g");
+        assert_eq!(paren.kind, TokenType::PipeGreater);
+        match &args[..] {
+            [Expr::Call { callee, args, .. }] => {
+                assert_eq!(callee_name(callee), "f");
+                assert_eq!(callee_name(&args[0]), "x");
+            }
+            args => panic!("Expected nested call, got {args:?}"),
+        }
+    }
+
+    #[test]
+    fn binds_looser_than_arithmetic() {
+        let Expr::Binary { left, op, .. } = parse_expr("1 + 2 |> f == 3;") else {
+            panic!("Expected equality");
+        };
+
+        assert_eq!(op.kind, TokenType::EqEq);
+        let Expr::Call { args, .. } = *left else {
+            panic!("Expected call");
+        };
+        assert!(matches!(
+            &args[..],
+            [Expr::Binary { left, .. }] if matches!(**left, Expr::Literal(Value::Number(n)) if n == 1.)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod lambda_tests {
+    use crate::{
+        ast::{stmt::Stmt, Expr},
+        parser::Parser,
+        scanner::Scanner,
+    };
+
+    fn parse_lambda_body(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let mut stmts = Parser::new(source, tokens).parse().unwrap();
+        match stmts.remove(0) {
+            Stmt::Var {
+                initializer: Expr::Lambda { body, .. },
+                ..
+            } => body,
+            stmt => panic!("Expected lambda declaration, got {stmt:?}"),
+        }
+    }
+
+    #[test]
+    fn expression_body() {
+        let body = parse_lambda_body("var f = fun (x) => x * 2;");
+        assert!(matches!(
+            &body[..],
+            [Stmt::Return {
+                val: Expr::Binary { .. },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn trailing_expression_is_result() {
+        let body = parse_lambda_body("var f = fun (x) { var y = x; y };");
+        assert!(matches!(
+            &body[..],
+            [
+                Stmt::Var { .. },
+                Stmt::Return {
+                    val: Expr::Variable { .. },
+                    ..
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn terminated_expression_is_not_result() {
+        let body = parse_lambda_body("var f = fun (x) { x; };");
+        assert!(matches!(&body[..], [Stmt::Expr(Expr::Variable { .. })]));
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use crate::{
+        ast::{stmt::Stmt, Expr},
+        parser::Parser,
+        scanner::Scanner,
+    };
+
+    fn parse_expr(source: &str) -> Expr {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let mut stmts = Parser::new(source, tokens).parse().unwrap();
+        match stmts.remove(0) {
+            Stmt::Expr(expr) => expr,
+            stmt => panic!("Expected expression statement, got {stmt:?}"),
+        }
+    }
+
+    fn parse_error(source: &str) -> String {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        Parser::new(source, tokens)
+            .parse()
+            .unwrap_err()
+            .remove(0)
+            .msg
+    }
+
+    #[test]
+    fn trailing_comma_makes_tuple() {
+        assert!(matches!(parse_expr("(1);"), Expr::Grouping(_)));
+        assert!(matches!(parse_expr("(1,);"), Expr::Tuple(items) if items.len() == 1));
+        assert!(matches!(parse_expr("(1, 2,);"), Expr::Tuple(items) if items.len() == 2));
+        assert!(matches!(parse_expr("();"), Expr::Tuple(items) if items.is_empty()));
+    }
+
+    #[test]
+    fn nested_tuple_index() {
+        let Expr::Get { object, name } = parse_expr("t.0.1;") else {
+            panic!("Expected field access");
+        };
+
+        assert_eq!(name.get_name(), "1");
+        assert!(matches!(*object, Expr::Get { name, .. } if name.get_name() == "0"));
+    }
+
+    #[test]
+    fn records_are_immutable() {
+        assert_eq!(
+            parse_error("var r = { x: 1, x: 2 };"),
+            "Duplicate field in record"
+        );
+        assert_eq!(
+            parse_error("r.x = 1;"),
+            "Can't assign to a field of an immutable value"
+        );
+        assert_eq!(parse_error("t.0x1;"), "Invalid tuple index");
+    }
+
+    #[test]
+    fn type_annotations() {
+        let source = "fun f(a: string, b): bool {}";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let Stmt::Function { signature, .. } =
+            Parser::new(source, tokens).parse().unwrap().remove(0)
+        else {
+            panic!("Expected function declaration");
+        };
+
+        assert_eq!(signature.params.len(), 2);
+        assert!(signature.params[0].is_some() && signature.params[1].is_none());
+        assert!(signature.ret.is_some());
+        assert_eq!(parse_error("var x: = 1;"), "Expect type after ':'");
+        assert_eq!(parse_error("var x: foo = 1;"), "Unknown type 'foo'");
+    }
+
+    #[test]
+    fn decorators() {
+        let source = "@a @b(1) async fun f() {}";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let Stmt::Function {
+            decorators,
+            is_async,
+            ..
+        } = Parser::new(source, tokens).parse().unwrap().remove(0)
+        else {
+            panic!("Expected function declaration");
+        };
+
+        assert!(is_async);
+        assert!(matches!(
+            &decorators[..],
+            [(_, Expr::Variable { .. }), (_, Expr::Call { .. })]
+        ));
+        assert_eq!(
+            parse_error("@a var x = 1;"),
+            "Expect function declaration after decorator"
+        );
+    }
+}
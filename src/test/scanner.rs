@@ -1,8 +1,10 @@
 #[cfg(test)]
 mod tests {
-    const BASE_PATH: &'static str = "test/scanning/";
+    const BASE_PATH: &str = "test/scanning/";
 
+    use crate::error::Error;
     use crate::scanner::Scanner;
+    use crate::token::Token;
     use std::fs::File;
     use std::io::Read;
 
@@ -28,6 +30,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unicode_identifiers() {
+        let path = format!("{BASE_PATH}unicode_identifiers.lox");
+
+        let tokens = get_tokens(&path);
+        let names: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Identifier переменная Nil",
+                "Identifier _имя Nil",
+                "Identifier caf\u{e9} Nil",
+                "Identifier caf\u{e9} Nil",
+                "Identifier 変数 Nil",
+                "Identifier x\u{661} Nil",
+                "Eof  Nil",
+            ]
+        );
+
+        // Positions count characters, not bytes
+        let positions: Vec<usize> = tokens[..6].iter().map(|t| t.pos).collect();
+        assert_eq!(positions, vec![10, 15, 20, 26, 29, 32]);
+    }
+
     #[test]
     fn keywords() {
         let path = format!("{BASE_PATH}keywords.lox");
@@ -37,14 +63,25 @@ mod tests {
             tokens,
             vec![
                 "And and Nil",
+                "Assert assert Nil",
+                "Async async Nil",
+                "Await await Nil",
+                "Break break Nil",
                 "Class class Nil",
+                "Continue continue Nil",
+                "Defer defer Nil",
+                "Do do Nil",
                 "Else else Nil",
+                "Ensures ensures Nil",
                 "False false Nil",
                 "For for Nil",
                 "Fun fun Nil",
                 "If if Nil",
+                "In in Nil",
+                "Loop loop Nil",
                 "Nil nil Nil",
                 "Or or Nil",
+                "Requires requires Nil",
                 "Return return Nil",
                 "Super super Nil",
                 "This this Nil",
@@ -70,11 +107,67 @@ mod tests {
                 "Number 456 456",
                 "Number 123 123",
                 "Dot . Nil",
+                "Number 1_000_000 1000000",
+                "Number 0xFF 255",
+                "Number 0Xab_cd 43981",
+                "Number 0b1010 10",
+                "Number 0o777 511",
+                "Number 1e3 1000",
+                "Number 2.5E-3 0.0025",
+                "Number 6e+2 600",
                 "Eof  Nil",
             ]
         );
     }
 
+    #[test]
+    fn malformed_numbers() {
+        let cases = [
+            ("0x", "Invalid number literal: Expected digits after '0x'"),
+            ("0b", "Invalid number literal: Expected digits after '0b'"),
+            (
+                "0b102",
+                "Invalid number literal: Invalid digit '2' in binary literal",
+            ),
+            (
+                "0o78",
+                "Invalid number literal: Invalid digit '8' in octal literal",
+            ),
+            (
+                "0xFG",
+                "Invalid number literal: Invalid digit 'G' in hexadecimal literal",
+            ),
+            ("1e", "Invalid number literal: Expected digits in exponent"),
+            ("1e+", "Invalid number literal: Expected digits in exponent"),
+            (
+                "1_",
+                "Invalid number literal: Separator '_' must be between digits",
+            ),
+            (
+                "1__0",
+                "Invalid number literal: Separator '_' must be between digits",
+            ),
+            (
+                "0x_1",
+                "Invalid number literal: Separator '_' must be between digits",
+            ),
+        ];
+
+        for (source, msg) in cases {
+            match Scanner::new(source).scan_tokens() {
+                Err(Error::ScannerError(e)) => assert_eq!(e.msg, msg, "source: {source}"),
+                res => panic!("Expected scan error for {source}, got {res:?}"),
+            }
+        }
+
+        // Escapes in block strings are blamed on their own line
+        let source = "var s = \"\"\"\n    fine\n    \\q\n    \"\"\";";
+        match Scanner::new(source).scan_tokens() {
+            Err(Error::ScannerError(e)) => assert_eq!(e.line, 3),
+            res => panic!("Expected scan error, got {res:?}"),
+        }
+    }
+
     #[test]
     fn punctuators() {
         let path = format!("{BASE_PATH}punctuators.lox");
@@ -88,6 +181,7 @@ mod tests {
                 "LeftBrace { Nil",
                 "RightBrace } Nil",
                 "Semicolon ; Nil",
+                "Colon : Nil",
                 "Comma , Nil",
                 "Plus + Nil",
                 "Minus - Nil",
@@ -101,6 +195,10 @@ mod tests {
                 "Greater > Nil",
                 "Slash / Nil",
                 "Dot . Nil",
+                "PipeGreater |> Nil",
+                "FatArrow => Nil",
+                "HashBrace #{ Nil",
+                "At @ Nil",
                 "Eof  Nil",
             ]
         );
@@ -113,10 +211,47 @@ mod tests {
         let tokens = get_tokens_string(&path);
         assert_eq!(
             tokens,
-            vec![r#"String "" "#, r#"String "string" string"#, r#"Eof  Nil"#,]
+            vec![
+                r#"String "" "#,
+                r#"String "string" string"#,
+                r#"String r"C:\path\n" C:\path\n"#,
+                "String \"\\x41\\u{1F600}\\t\" A\u{1F600}\t",
+                r#"Eof  Nil"#,
+            ]
         );
     }
 
+    #[test]
+    fn multiline_string() {
+        let path = format!("{BASE_PATH}multiline_string.lox");
+
+        let tokens = get_tokens(&path);
+        assert_eq!(tokens[3].literal.to_string(), "first\n  second\t!\nthird");
+        assert_eq!(tokens[3].line, 5);
+        assert_eq!(tokens[5].lexeme, "end");
+        assert_eq!(tokens[5].line, 6);
+    }
+
+    #[test]
+    fn malformed_strings() {
+        let cases = [
+            ("\"abc\ndef\"", "Unterminated string"),
+            ("\"\"\"abc", "Unterminated string"),
+            ("\"\\q\"", "Unsupported escape symbol '\\q'"),
+            ("\"\\x4\"", "Expected two hex digits after '\\x'"),
+            ("\"\\u41\"", "Expected '{' after '\\u'"),
+            ("\"\\u{41\"", "Expected 1 to 6 hex digits in '\\u{...}'"),
+            ("\"\\u{D800}\"", "Invalid unicode escape '\\u{D800}'"),
+        ];
+
+        for (source, msg) in cases {
+            match Scanner::new(source).scan_tokens() {
+                Err(Error::ScannerError(e)) => assert_eq!(e.msg, msg, "source: {source}"),
+                res => panic!("Expected scan error for {source}, got {res:?}"),
+            }
+        }
+    }
+
     #[test]
     fn whitespace() {
         let path = format!("{BASE_PATH}whitespace.lox");
@@ -135,13 +270,15 @@ mod tests {
     }
 
     fn get_tokens_string(path: &str) -> Vec<String> {
+        get_tokens(path).iter().map(|t| t.to_string()).collect()
+    }
+
+    fn get_tokens(path: &str) -> Vec<Token> {
         let mut file = File::options().read(true).open(path).unwrap();
         let mut buf = String::new();
         file.read_to_string(&mut buf).unwrap();
 
         let scanner = Scanner::new(&buf);
-        let tokens = scanner.scan_tokens().unwrap();
-
-        tokens.iter().map(|t| t.to_string()).collect()
+        scanner.scan_tokens().unwrap()
     }
 }
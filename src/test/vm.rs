@@ -0,0 +1,166 @@
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, rc::Rc};
+
+    use crate::{
+        ast::TokenAstInfo,
+        interpreter::Interpreter,
+        parser::Parser,
+        resolver::Resolver,
+        scanner::Scanner,
+        token::Token,
+        tokentype::TokenType,
+        value::Value,
+        vm::{
+            chunk::{Op, Proto},
+            compiler::Compiler,
+            disassembler::disassemble,
+            loxc::{self, VERSION},
+        },
+    };
+
+    fn compile(source: &str) -> (Interpreter, Proto) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let ast = Parser::new(source, tokens).parse().unwrap();
+
+        Resolver::new().resolve(&ast).unwrap();
+        let script = Compiler::new().compile(&ast);
+
+        (Interpreter::new(), script)
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> Value {
+        let token = Token::new(TokenType::Identifier, name.into(), Value::Nil, 0, 0, 0);
+        interpreter
+            .globals
+            .borrow()
+            .get(&TokenAstInfo::from(&token))
+            .unwrap()
+    }
+
+    const SCRIPT: &str = "
+        fun adder(n: number): fun requires n >= 0 {
+            return fun(x) => x + n;
+        }
+        var add2 = adder(2);
+        var total = 0;
+        for (var i = 0; i < 4; i = i + 1) {
+            if (i == 2) continue;
+            total = total + add2(i);
+        }
+        var point = {x: 1, y: \"two\"};
+        var tags = #{\"a\", \"b\"};
+        var summary = (total, point.y, 3 in tags);
+    ";
+
+    #[test]
+    fn loxc_round_trip() {
+        let (mut interpreter, script) = compile(SCRIPT);
+        let bytes = loxc::encode(&script);
+
+        let decoded = loxc::decode(&bytes).unwrap();
+        assert_eq!(loxc::encode(&decoded), bytes);
+
+        interpreter.run_compiled(decoded).unwrap();
+        assert_eq!(global(&interpreter, "total").to_string(), "10");
+        assert_eq!(
+            global(&interpreter, "summary").to_string(),
+            "(10, two, false)"
+        );
+    }
+
+    #[test]
+    fn loxc_rejects_invalid_files() {
+        let (_, script) = compile(SCRIPT);
+        let bytes = loxc::encode(&script);
+
+        let err = loxc::decode(b"LOXX\x01\x00").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Not a .loxc file");
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = loxc::decode(&newer).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unsupported .loxc version {}, expected {VERSION}",
+                VERSION + 1
+            )
+        );
+
+        let err = loxc::decode(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated .loxc file");
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        let err = loxc::decode(&trailing).unwrap_err();
+        assert_eq!(err.to_string(), "Trailing bytes after the script");
+    }
+
+    #[test]
+    fn loxc_rejects_corrupted_bytecode() {
+        let source = "
+            var log = \"\";
+            fun clamp(x) requires x != nil ensures result >= 0 {
+                defer log = log + \"c\";
+                var lo = 0;
+                var keep = fun () => lo or x;
+                assert keep() and true, \"kept\";
+                if (x < lo) return lo;
+                return x;
+            }
+            var total = clamp(3) + clamp(-1);
+        ";
+        let (_, script) = compile(source);
+        let bytes = loxc::encode(&script);
+
+        // Corrupted files either fail to load or run without panicking
+        for i in 6..bytes.len() {
+            for flip in [1, 2, 4, 8, 16, 32, 64, 128] {
+                let mut corrupted = bytes.clone();
+                corrupted[i] ^= flip;
+                match loxc::decode(&corrupted) {
+                    Ok(script) => {
+                        let mut interpreter = Interpreter::new();
+                        interpreter.set_step_budget(Some(10_000));
+                        let _ = interpreter.run_compiled(script);
+                    }
+                    Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData),
+                }
+            }
+        }
+
+        let mut bad_slot = script;
+        let code = &mut Rc::get_mut(&mut bad_slot.chunk.protos[0])
+            .unwrap()
+            .chunk
+            .code;
+        let get = code.iter().position(|op| matches!(op, Op::GetLocal(_)));
+        code[get.unwrap()] = Op::GetLocal(100);
+        let err = loxc::decode(&loxc::encode(&bad_slot)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Invalid slot at op"));
+    }
+
+    #[test]
+    fn token_types_are_numbered_in_order() {
+        for (i, kind) in TokenType::ALL.iter().enumerate() {
+            assert_eq!(*kind as usize, i);
+        }
+    }
+
+    #[test]
+    fn disassembly_lists_ops_and_source() {
+        let source = "var a = 1;\nfun f(x) {\n  return x + a;\n}\nprint f(2);\n";
+        let (_, script) = compile(source);
+        let listing = disassemble(&script, Some(source));
+
+        assert!(listing.starts_with("== <script> ==\n"));
+        assert!(listing.contains("        1 | var a = 1;\n"));
+        assert!(listing.contains("DefineGlobal(0)"));
+        assert!(listing.contains("== <fun 'f'> ==\n"));
+        assert!(listing.contains("        3 | return x + a;\n"));
+        assert!(listing.contains("GetGlobal(0)"));
+    }
+}
@@ -2,6 +2,9 @@ use crate::{tokentype::TokenType, value::Value};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
+    /// Position of the first character, counted in characters like `pos`
+    pub start: usize,
+    /// Position after the last character
     pub pos: usize,
     pub r#type: TokenType,
     pub lexeme: String,
@@ -10,8 +13,16 @@ pub struct Token {
 }
 
 impl Token {
-    pub fn new(r#type: TokenType, lexeme: String, literal: Value, line: usize, pos: usize) -> Self {
+    pub fn new(
+        r#type: TokenType,
+        lexeme: String,
+        literal: Value,
+        line: usize,
+        start: usize,
+        pos: usize,
+    ) -> Self {
         Self {
+            start,
             pos,
             r#type,
             lexeme,
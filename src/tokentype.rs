@@ -8,6 +8,7 @@ pub enum TokenType {
     LeftBrace,
     RightBrace,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -15,6 +16,7 @@ pub enum TokenType {
     Percent,
     Slash,
     Star,
+    At,
 
     // Токены из одного или двух символов
     Bang,
@@ -25,6 +27,9 @@ pub enum TokenType {
     GreaterEq,
     Less,
     LessEq,
+    PipeGreater,
+    FatArrow,
+    HashBrace,
 
     // Литералы
     Identifier,
@@ -33,15 +38,26 @@ pub enum TokenType {
 
     // Ключевые слова
     And,
+    Assert,
+    Async,
+    Await,
+    Break,
     Class,
+    Continue,
+    Defer,
+    Do,
     Else,
+    Ensures,
     False,
     Fun,
     For,
     If,
+    In,
+    Loop,
     Nil,
     Or,
     Print,
+    Requires,
     Return,
     Super,
     This,
@@ -55,18 +71,91 @@ pub enum TokenType {
 
 use TokenType::*;
 
+impl TokenType {
+    /// Every token type in declaration order, so `ALL[kind as usize] == kind`
+    pub const ALL: [TokenType; 56] = [
+        LeftParen,
+        RightParen,
+        LeftBrace,
+        RightBrace,
+        Comma,
+        Colon,
+        Dot,
+        Minus,
+        Plus,
+        Semicolon,
+        Percent,
+        Slash,
+        Star,
+        At,
+        Bang,
+        BangEq,
+        Eq,
+        EqEq,
+        Greater,
+        GreaterEq,
+        Less,
+        LessEq,
+        PipeGreater,
+        FatArrow,
+        HashBrace,
+        Identifier,
+        String,
+        Number,
+        And,
+        Assert,
+        Async,
+        Await,
+        Break,
+        Class,
+        Continue,
+        Defer,
+        Do,
+        Else,
+        Ensures,
+        False,
+        Fun,
+        For,
+        If,
+        In,
+        Loop,
+        Nil,
+        Or,
+        Print,
+        Requires,
+        Return,
+        Super,
+        This,
+        True,
+        Var,
+        While,
+        Eof,
+    ];
+}
+
 lazy_static::lazy_static! {
     pub static ref KEYWORDS: HashMap<&'static str, TokenType> = HashMap::from([
         ("and", And),
+        ("assert", Assert),
+        ("async", Async),
+        ("await", Await),
+        ("break", Break),
         ("class", Class),
+        ("continue", Continue),
+        ("defer", Defer),
+        ("do", Do),
         ("else", Else),
+        ("ensures", Ensures),
         ("false", False),
         ("for", For),
         ("fun", Fun),
         ("if", If),
+        ("in", In),
+        ("loop", Loop),
         ("nil", Nil),
         ("or", Or),
         ("print", Print),
+        ("requires", Requires),
         ("return", Return),
         ("super", Super),
         ("this", This),
@@ -85,6 +174,7 @@ impl std::fmt::Display for TokenType {
             LeftBrace => "{",
             RightBrace => "+",
             Comma => ",",
+            Colon => ":",
             Dot => ".",
             Minus => "-",
             Plus => "+",
@@ -92,6 +182,7 @@ impl std::fmt::Display for TokenType {
             Percent => "%",
             Slash => "/",
             Star => "*",
+            At => "@",
 
             // Токены из одного или двух символов
             Bang => "!",
@@ -102,6 +193,9 @@ impl std::fmt::Display for TokenType {
             GreaterEq => ">=",
             Less => "<",
             LessEq => "<=",
+            PipeGreater => "|>",
+            FatArrow => "=>",
+            HashBrace => "#{",
 
             // Литералы
             Identifier => "Identifier",
@@ -110,15 +204,26 @@ impl std::fmt::Display for TokenType {
 
             // Ключевые слова
             And => "and",
+            Assert => "assert",
+            Async => "async",
+            Await => "await",
+            Break => "break",
             Class => "class",
+            Continue => "continue",
+            Defer => "defer",
+            Do => "do",
             Else => "else",
+            Ensures => "ensures",
             False => "false",
             Fun => "fun",
             For => "for",
             If => "if",
+            In => "in",
+            Loop => "loop",
             Nil => "nil",
             Or => "or",
             Print => "print",
+            Requires => "requires",
             Return => "return",
             Super => "super",
             This => "this",
@@ -0,0 +1,166 @@
+mod tables;
+
+use tables::*;
+
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = HANGUL_L_COUNT * HANGUL_N_COUNT;
+
+pub fn is_xid_start(c: char) -> bool {
+    if c.is_ascii() {
+        return c.is_ascii_alphabetic();
+    }
+    in_ranges(XID_START, c as u32)
+}
+
+pub fn is_xid_continue(c: char) -> bool {
+    if c.is_ascii() {
+        return c.is_ascii_alphanumeric() || c == '_';
+    }
+    in_ranges(XID_CONTINUE, c as u32)
+}
+
+/// Converts the string into Normalization Form C, so that identifiers typed
+/// with precomposed and combining characters refer to the same variable.
+pub fn to_nfc(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+
+    let mut chars = vec![];
+    for c in s.chars() {
+        decompose(c as u32, &mut chars);
+    }
+    reorder(&mut chars);
+    compose(&mut chars);
+
+    chars.into_iter().filter_map(char::from_u32).collect()
+}
+
+fn in_ranges(table: &[(u32, u32)], c: u32) -> bool {
+    table
+        .binary_search_by(|&(lo, hi)| {
+            if hi < c {
+                std::cmp::Ordering::Less
+            } else if lo > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+fn combining_class(c: u32) -> u8 {
+    COMBINING_CLASS
+        .binary_search_by(|&(lo, hi, _)| {
+            if hi < c {
+                std::cmp::Ordering::Less
+            } else if lo > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .map(|i| COMBINING_CLASS[i].2)
+        .unwrap_or(0)
+}
+
+fn decompose(c: u32, out: &mut Vec<u32>) {
+    if (HANGUL_S_BASE..HANGUL_S_BASE + HANGUL_S_COUNT).contains(&c) {
+        let index = c - HANGUL_S_BASE;
+        out.push(HANGUL_L_BASE + index / HANGUL_N_COUNT);
+        out.push(HANGUL_V_BASE + (index % HANGUL_N_COUNT) / HANGUL_T_COUNT);
+        if !index.is_multiple_of(HANGUL_T_COUNT) {
+            out.push(HANGUL_T_BASE + index % HANGUL_T_COUNT);
+        }
+        return;
+    }
+
+    match DECOMPOSITION.binary_search_by_key(&c, |&(c, ..)| c) {
+        Ok(i) => {
+            let (_, first, second) = DECOMPOSITION[i];
+            decompose(first, out);
+            if second != 0 {
+                decompose(second, out);
+            }
+        }
+        Err(_) => out.push(c),
+    }
+}
+
+/// Sorts every run of non-starters by combining class, keeping the original
+/// order of characters with equal classes.
+fn reorder(chars: &mut [u32]) {
+    let mut start = 0;
+    while start < chars.len() {
+        if combining_class(chars[start]) == 0 {
+            start += 1;
+            continue;
+        }
+
+        let mut end = start;
+        while end < chars.len() && combining_class(chars[end]) != 0 {
+            end += 1;
+        }
+        chars[start..end].sort_by_key(|c| combining_class(*c));
+        start = end;
+    }
+}
+
+fn compose(chars: &mut Vec<u32>) {
+    let mut result: Vec<u32> = Vec::with_capacity(chars.len());
+    let mut starter = None;
+    let mut last_class = 0;
+
+    for &c in chars.iter() {
+        let class = combining_class(c);
+        if let Some(starter) = starter {
+            let blocked = result.len() - 1 != starter && (last_class == 0 || last_class >= class);
+            if !blocked {
+                if let Some(composed) = compose_pair(result[starter], c) {
+                    result[starter] = composed;
+                    continue;
+                }
+            }
+        }
+
+        if class == 0 {
+            starter = Some(result.len());
+        }
+        last_class = class;
+        result.push(c);
+    }
+
+    *chars = result;
+}
+
+fn compose_pair(first: u32, second: u32) -> Option<u32> {
+    let l_range = HANGUL_L_BASE..HANGUL_L_BASE + HANGUL_L_COUNT;
+    let v_range = HANGUL_V_BASE..HANGUL_V_BASE + HANGUL_V_COUNT;
+    if l_range.contains(&first) && v_range.contains(&second) {
+        let l = first - HANGUL_L_BASE;
+        let v = second - HANGUL_V_BASE;
+        return Some(HANGUL_S_BASE + (l * HANGUL_V_COUNT + v) * HANGUL_T_COUNT);
+    }
+
+    let s_range = HANGUL_S_BASE..HANGUL_S_BASE + HANGUL_S_COUNT;
+    let t_range = HANGUL_T_BASE + 1..HANGUL_T_BASE + HANGUL_T_COUNT;
+    if s_range.contains(&first)
+        && (first - HANGUL_S_BASE).is_multiple_of(HANGUL_T_COUNT)
+        && t_range.contains(&second)
+    {
+        return Some(first + second - HANGUL_T_BASE);
+    }
+
+    COMPOSITION
+        .binary_search_by_key(&(first, second), |&(a, b, _)| (a, b))
+        .map(|i| COMPOSITION[i].2)
+        .ok()
+}
@@ -1,16 +1,225 @@
-use crate::function::Function;
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
 
+use crate::{event_loop::Promise, fiber::Channel, function::Function};
+
+/// Strings are immutable and shared, `Rc` compares pointers before contents
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Nil,
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Bool(bool),
     Fun(Function),
+    Tuple(Rc<[Value]>),
+    Record(Rc<Record>),
+    Set(Rc<Set>),
+    Channel(Rc<Channel>),
+    Promise(Rc<Promise>),
 }
 
 impl Eq for Value {}
 
+impl Value {
+    /// Functions can't be compared meaningfully, so neither they nor
+    /// aggregates containing them can be set elements
+    pub fn is_hashable(&self) -> bool {
+        match self {
+            Value::Fun(_) | Value::Channel(_) | Value::Promise(_) => false,
+            Value::Tuple(items) => items.iter().all(Value::is_hashable),
+            Value::Record(record) => record.fields.iter().all(|(_, v)| v.is_hashable()),
+            _ => true,
+        }
+    }
+
+    /// Name reported by the `type` native
+    pub fn type_name(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(_) => "bool".to_string(),
+            Value::Number(_) => "number".to_string(),
+            Value::String(_) => "string".to_string(),
+            Value::Fun(_) => self.to_string(),
+            Value::Tuple(_) => "tuple".to_string(),
+            Value::Record(_) => "record".to_string(),
+            Value::Set(_) => "set".to_string(),
+            Value::Channel(_) => "channel".to_string(),
+            Value::Promise(_) => "promise".to_string(),
+        }
+    }
+}
+
+/// Consistent with `Value::eq`: `-0.0` hashes as `0.0` and all NaNs hash the
+/// same, records and sets hash independently of their order
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Nil | Value::Fun(_) | Value::Channel(_) | Value::Promise(_) => (),
+            Value::Number(n) => {
+                let n = if *n == 0. {
+                    0.
+                } else if n.is_nan() {
+                    f64::NAN
+                } else {
+                    *n
+                };
+                n.to_bits().hash(state);
+            }
+            Value::String(s) => s.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Tuple(items) => items.hash(state),
+            Value::Record(record) => {
+                unordered_hash(record.fields.iter()).hash(state);
+            }
+            Value::Set(set) => unordered_hash(set.items.iter()).hash(state),
+        }
+    }
+}
+
+fn unordered_hash<T: Hash>(items: impl Iterator<Item = T>) -> u64 {
+    items.fold(0u64, |acc, item| {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        acc.wrapping_add(hasher.finish())
+    })
+}
+
+/// Immutable set of named fields, printed in the order they were written
+#[derive(Debug, Clone)]
+pub struct Record {
+    fields: Vec<(Rc<str>, Value)>,
+}
+
+impl Record {
+    pub fn new(fields: Vec<(Rc<str>, Value)>) -> Self {
+        Self { fields }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.fields.iter().map(|(_, val)| val)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|(field, _)| &**field == name)
+            .map(|(_, val)| val)
+    }
+}
+
+/// Records are equal if they have the same fields regardless of their order
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        self.fields.len() == other.fields.len()
+            && self
+                .fields
+                .iter()
+                .all(|(name, val)| other.get(name) == Some(val))
+    }
+}
+
+/// Set element, equal to another one if the values are equal or both are NaN,
+/// also inside tuples and records, so a NaN can be found in the set it was
+/// added to
+#[derive(Debug, Clone)]
+struct SetKey(Value);
+
+impl PartialEq for SetKey {
+    fn eq(&self, other: &Self) -> bool {
+        same_key(&self.0, &other.0)
+    }
+}
+
+/// Equality of set elements, matching the hash that treats all NaNs alike
+fn same_key(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Number(l), Value::Number(r)) => l == r || l.is_nan() && r.is_nan(),
+        (Value::Tuple(l), Value::Tuple(r)) => {
+            l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| same_key(l, r))
+        }
+        (Value::Record(l), Value::Record(r)) => {
+            l.fields.len() == r.fields.len()
+                && (l.fields.iter())
+                    .all(|(name, val)| r.get(name).is_some_and(|r| same_key(val, r)))
+        }
+        (l, r) => l == r,
+    }
+}
+
+impl Eq for SetKey {}
+
+impl Hash for SetKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+/// Immutable set of hashable values, iterated in insertion order
+#[derive(Debug, Clone, Default)]
+pub struct Set {
+    items: Vec<Value>,
+    keys: HashSet<SetKey>,
+}
+
+impl Set {
+    /// Callers must check `Value::is_hashable` first
+    pub fn insert(&mut self, val: Value) {
+        if self.keys.insert(SetKey(val.clone())) {
+            self.items.push(val);
+        }
+    }
+
+    pub fn contains(&self, val: &Value) -> bool {
+        self.keys.contains(&SetKey(val.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.items.iter()
+    }
+
+    pub fn union(&self, other: &Set) -> Set {
+        let mut set = self.clone();
+        for item in other.iter() {
+            set.insert(item.clone());
+        }
+        set
+    }
+
+    pub fn intersection(&self, other: &Set) -> Set {
+        self.filtered(|item| other.contains(item))
+    }
+
+    pub fn difference(&self, other: &Set) -> Set {
+        self.filtered(|item| !other.contains(item))
+    }
+
+    fn filtered(&self, keep: impl Fn(&Value) -> bool) -> Set {
+        let mut set = Set::default();
+        for item in self.iter().filter(|item| keep(item)) {
+            set.insert(item.clone());
+        }
+        set
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|item| other.contains(item))
+    }
+}
+
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Value::*;
@@ -26,7 +235,32 @@ impl std::fmt::Display for Value {
                     format!("<lox fun '{}'>", name.get_name())
                 }
                 Function::LoxLambda { .. } => "<lox lambda>".into(),
+                Function::Closure(closure) => match &closure.proto.name {
+                    Some(name) => format!("<lox fun '{}'>", name.get_name()),
+                    None => "<lox lambda>".into(),
+                },
+            },
+            Tuple(items) => match &items[..] {
+                [item] => format!("({item},)"),
+                items => {
+                    let items: Vec<_> = items.iter().map(|i| i.to_string()).collect();
+                    format!("({})", items.join(", "))
+                }
             },
+            Record(record) => {
+                let fields: Vec<_> = record
+                    .fields
+                    .iter()
+                    .map(|(name, val)| format!("{name}: {val}"))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+            Set(set) => {
+                let items: Vec<_> = set.iter().map(|i| i.to_string()).collect();
+                format!("#{{{}}}", items.join(", "))
+            }
+            Channel(_) => "<channel>".into(),
+            Promise(_) => "<promise>".into(),
         };
         write!(f, "{to_write}")
     }
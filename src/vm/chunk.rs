@@ -0,0 +1,142 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{types::Signature, TokenAstInfo},
+    value::Value,
+};
+
+/// Operands index the stack relative to the frame base, or the tables of the
+/// chunk. Jump targets are absolute positions in `Chunk::code`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Constant(u32),
+    Nil,
+    True,
+    False,
+    Pop,
+    PopN(u32),
+    /// Placeholder for the `MakeCell` of a local that is never captured
+    Nop,
+    GetLocal(u32),
+    SetLocal(u32),
+    /// Moves a local into a cell shared with the closures capturing it,
+    /// locals are read and written through their cell from then on
+    MakeCell(u32),
+    GetUpvalue(u32),
+    SetUpvalue(u32),
+    /// Globals are looked up by the name of the token
+    GetGlobal(u32),
+    SetGlobal(u32),
+    DefineGlobal(u32),
+    /// Arithmetic, comparison or `in`, selected by the operator token
+    Binary(u32),
+    /// Like `Binary` but keeps the operands below the result, they are
+    /// reported when an assertion fails
+    BinaryKeep(u32),
+    Negate(u32),
+    Not,
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    JumpIfFalseOrPop(u32),
+    JumpIfTrueOrPop(u32),
+    /// Skips assertions and contracts when they are disabled
+    JumpIfNoAssert(u32),
+    /// Argument count and closing parenthesis
+    Call(u32, u32),
+    /// Call in a `return`, which replaces the frame of the caller unless its
+    /// result is checked or expressions were deferred in it
+    TailCall(u32, u32),
+    Closure(u32),
+    Return,
+    Print(u32),
+    Tuple(u32),
+    Set,
+    /// Adds the value on top of the stack to the set below it, errors are
+    /// reported at the brace
+    SetInsert(u32),
+    /// Token of the first field name and field count
+    Record(u32, u32),
+    GetField(u32),
+    Await(u32),
+    /// Applies the decorator below the function on top of the stack
+    Decorate(u32),
+    /// Registers the deferred expression that follows at the given block
+    /// depth, then jumps past it
+    Defer(u32, u32),
+    /// Runs the expressions deferred at the given depth or deeper
+    RunDeferred(u32),
+    EndDefer,
+    /// Keyword, source constant, and whether a message and comparison
+    /// operands are on the stack
+    AssertFailed(u32, u32, bool, bool),
+    Require(u32),
+    Ensure(u32),
+    /// Leaves the function once its postconditions hold
+    EndEnsures,
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// Source line of every op
+    pub lines: Vec<usize>,
+    pub constants: Vec<Value>,
+    /// Names and error locations referenced by ops
+    pub tokens: Vec<TokenAstInfo>,
+    /// Functions declared in this one
+    pub protos: Vec<Rc<Proto>>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, op: Op, line: usize) -> usize {
+        self.code.push(op);
+        self.lines.push(line);
+        self.code.len() - 1
+    }
+}
+
+/// Variable captured by a closure, either a local of the enclosing function
+/// or one of its upvalues
+#[derive(Debug, Clone, Copy)]
+pub struct Capture {
+    pub is_local: bool,
+    pub index: u32,
+}
+
+/// Contract clause reported when it doesn't hold
+#[derive(Debug)]
+pub struct Condition {
+    pub keyword: TokenAstInfo,
+    pub source: String,
+}
+
+/// Compiled function. Slot `0` of its frame holds the callee, followed by the
+/// parameters and locals.
+#[derive(Debug, Default)]
+pub struct Proto {
+    /// `None` for lambdas and scripts
+    pub name: Option<TokenAstInfo>,
+    pub params: Vec<TokenAstInfo>,
+    pub signature: Signature,
+    pub is_async: bool,
+    pub captures: Vec<Capture>,
+    /// Indexed by `Require` and `Ensure`
+    pub conditions: Vec<Condition>,
+    /// Start of the postconditions, which see the returned value in the slot
+    /// after the parameters
+    pub ensures: Option<u32>,
+    pub chunk: Chunk,
+}
+
+impl Proto {
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<Value>>>,
+}
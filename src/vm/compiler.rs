@@ -0,0 +1,730 @@
+use std::rc::Rc;
+
+use crate::{
+    ast::{
+        stmt::{Clause, Contracts, Stmt},
+        types::Signature,
+        visitor::Visitor,
+        Expr, TokenAstInfo,
+    },
+    interpreter::{is_comparison, ungrouped},
+    tokentype::TokenType,
+    value::Value,
+};
+
+use super::chunk::{Capture, Chunk, Condition, Op, Proto};
+
+struct Local {
+    /// Empty for the callee and for a function whose decorators are still
+    /// being compiled
+    name: String,
+    depth: usize,
+    captured: bool,
+    /// Placeholder turned into `MakeCell` once the local is captured
+    decl: usize,
+}
+
+struct Loop {
+    label: Option<String>,
+    /// Scope depth and number of locals outside of the body
+    depth: usize,
+    locals: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// Function being compiled
+struct FunState {
+    proto: Proto,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// Whether each open block registered a deferred expression
+    deferring: Vec<bool>,
+    loops: Vec<Loop>,
+}
+
+impl FunState {
+    fn new(proto: Proto) -> Self {
+        let callee = Local {
+            name: String::new(),
+            depth: 0,
+            captured: false,
+            decl: 0,
+        };
+
+        Self {
+            proto,
+            locals: vec![callee],
+            scope_depth: 0,
+            deferring: vec![],
+            loops: vec![],
+        }
+    }
+}
+
+enum Place {
+    Local(u32),
+    Upvalue(u32),
+    Global,
+}
+
+/// Compiles a resolved script for the VM. Variables are resolved again here
+/// into stack slots, globals are the ones the resolver left unresolved.
+pub struct Compiler {
+    funs: Vec<FunState>,
+    /// Line of the last token seen, recorded for every emitted op
+    line: usize,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            funs: vec![],
+            line: 0,
+        }
+    }
+
+    pub fn compile(mut self, stmts: &[Stmt]) -> Proto {
+        self.funs.push(FunState::new(Proto::default()));
+        for stmt in stmts {
+            self.visit_statement(stmt);
+        }
+        self.emit(Op::Nil);
+        self.emit(Op::RunDeferred(0));
+        self.emit(Op::Return);
+
+        self.funs.pop().unwrap().proto
+    }
+
+    fn fun(&mut self) -> &mut FunState {
+        self.funs.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.fun().proto.chunk
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let line = self.line;
+        self.chunk().write(op, line)
+    }
+
+    fn here(&mut self) -> u32 {
+        self.chunk().code.len() as u32
+    }
+
+    /// Points the jump at `at` to the next op
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        let op = &mut self.chunk().code[at];
+        *op = match *op {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::JumpIfTrue(_) => Op::JumpIfTrue(target),
+            Op::JumpIfFalseOrPop(_) => Op::JumpIfFalseOrPop(target),
+            Op::JumpIfTrueOrPop(_) => Op::JumpIfTrueOrPop(target),
+            Op::JumpIfNoAssert(_) => Op::JumpIfNoAssert(target),
+            Op::Defer(_, depth) => Op::Defer(target, depth),
+            op => unreachable!(
+                "Well, that shouldn't happen... ICE Code: 0x9: Patching {op:?} as a jump"
+            ),
+        };
+    }
+
+    fn constant(&mut self, val: Value) -> u32 {
+        let constants = &mut self.chunk().constants;
+        constants.push(val);
+        constants.len() as u32 - 1
+    }
+
+    fn token(&mut self, token: &TokenAstInfo) -> u32 {
+        self.line = token.line;
+        let tokens = &mut self.chunk().tokens;
+        tokens.push(token.clone());
+        tokens.len() as u32 - 1
+    }
+
+    fn pop(&mut self, n: usize) {
+        match n {
+            0 => (),
+            1 => {
+                self.emit(Op::Pop);
+            }
+            n => {
+                self.emit(Op::PopN(n as u32));
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        let fun = self.fun();
+        fun.scope_depth += 1;
+        fun.deferring.push(false);
+    }
+
+    fn end_scope(&mut self) {
+        let fun = self.fun();
+        let deferring = fun.deferring.pop().unwrap();
+        let depth = fun.scope_depth;
+        fun.scope_depth -= 1;
+        let outer = fun.locals.iter().filter(|l| l.depth < depth).count();
+        let count = fun.locals.len() - outer;
+        fun.locals.truncate(outer);
+
+        if deferring {
+            self.emit(Op::RunDeferred(depth as u32));
+        }
+        self.pop(count);
+    }
+
+    /// Names the value on top of the stack
+    fn add_local(&mut self, name: &str) {
+        let decl = self.emit(Op::Nop);
+        let fun = self.fun();
+        let depth = fun.scope_depth;
+        fun.locals.push(Local {
+            name: name.into(),
+            depth,
+            captured: false,
+            decl,
+        });
+    }
+
+    fn resolve_local(&self, level: usize, name: &str) -> Option<usize> {
+        self.funs[level].locals.iter().rposition(|l| l.name == name)
+    }
+
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<u32> {
+        if level == 0 {
+            return None;
+        }
+
+        let capture = if let Some(local) = self.resolve_local(level - 1, name) {
+            self.capture(level - 1, local);
+            Capture {
+                is_local: true,
+                index: local as u32,
+            }
+        } else {
+            Capture {
+                is_local: false,
+                index: self.resolve_upvalue(level - 1, name)?,
+            }
+        };
+
+        let captures = &mut self.funs[level].proto.captures;
+        let index = captures
+            .iter()
+            .position(|c| c.is_local == capture.is_local && c.index == capture.index)
+            .unwrap_or_else(|| {
+                captures.push(capture);
+                captures.len() - 1
+            });
+
+        Some(index as u32)
+    }
+
+    /// Moves the local into a cell when it is declared, so closures share it
+    /// with the function and outlive its frame
+    fn capture(&mut self, level: usize, local: usize) {
+        let fun = &mut self.funs[level];
+        let decl = fun.locals[local].decl;
+        if !fun.locals[local].captured {
+            fun.locals[local].captured = true;
+            fun.proto.chunk.code[decl] = Op::MakeCell(local as u32);
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Place {
+        let level = self.funs.len() - 1;
+        if let Some(local) = self.resolve_local(level, name) {
+            Place::Local(local as u32)
+        } else if let Some(upvalue) = self.resolve_upvalue(level, name) {
+            Place::Upvalue(upvalue)
+        } else {
+            Place::Global
+        }
+    }
+
+    fn get_variable(&mut self, name: &TokenAstInfo) {
+        self.line = name.line;
+        let op = match self.resolve(name.get_name()) {
+            Place::Local(slot) => Op::GetLocal(slot),
+            Place::Upvalue(upvalue) => Op::GetUpvalue(upvalue),
+            Place::Global => Op::GetGlobal(self.token(name)),
+        };
+        self.emit(op);
+    }
+
+    /// Assigns the value on top of the stack, leaving it there
+    fn set_variable(&mut self, name: &TokenAstInfo) {
+        self.line = name.line;
+        let op = match self.resolve(name.get_name()) {
+            Place::Local(slot) => Op::SetLocal(slot),
+            Place::Upvalue(upvalue) => Op::SetUpvalue(upvalue),
+            Place::Global => Op::SetGlobal(self.token(name)),
+        };
+        self.emit(op);
+    }
+
+    /// Compiles a loop body, returning its pending `break` and `continue`
+    /// jumps
+    fn loop_body(&mut self, label: &Option<TokenAstInfo>, body: &Stmt) -> Loop {
+        let fun = self.fun();
+        let depth = fun.scope_depth;
+        let locals = fun.locals.len();
+        fun.loops.push(Loop {
+            label: label.as_ref().map(|l| l.get_name().to_string()),
+            depth,
+            locals,
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.visit_statement(body);
+
+        self.fun().loops.pop().unwrap()
+    }
+
+    /// Leaves the blocks inside the target loop and jumps out of it, or to its
+    /// next iteration
+    fn loop_exit(&mut self, label: &Option<TokenAstInfo>, is_break: bool) {
+        let fun = self.fun();
+        let target = match label {
+            Some(label) => fun
+                .loops
+                .iter()
+                .rposition(|l| l.label.as_deref() == Some(label.get_name())),
+            None => fun.loops.len().checked_sub(1),
+        }
+        .unwrap_or_else(|| {
+            unreachable!("Well, that shouldn't happen... ICE Code: 0x9: Loop exit outside of loop")
+        });
+        let depth = fun.loops[target].depth;
+        let count = fun.locals.len() - fun.loops[target].locals;
+
+        self.emit(Op::RunDeferred(depth as u32 + 1));
+        self.pop(count);
+        let jump = self.emit(Op::Jump(0));
+
+        let target = &mut self.fun().loops[target];
+        if is_break {
+            target.breaks.push(jump);
+        } else {
+            target.continues.push(jump);
+        }
+    }
+
+    fn condition(&mut self, clause: &Clause) -> u32 {
+        let conditions = &mut self.fun().proto.conditions;
+        conditions.push(Condition {
+            keyword: clause.keyword.clone(),
+            source: clause.source.clone(),
+        });
+        conditions.len() as u32 - 1
+    }
+
+    /// Compiles a function and emits its closure
+    fn function(
+        &mut self,
+        name: Option<&TokenAstInfo>,
+        params: &[TokenAstInfo],
+        signature: &Signature,
+        body: &[Stmt],
+        is_async: bool,
+        contracts: Option<&Contracts>,
+    ) {
+        self.funs.push(FunState::new(Proto {
+            name: name.cloned(),
+            params: params.to_vec(),
+            signature: signature.clone(),
+            is_async,
+            ..Default::default()
+        }));
+        self.begin_scope();
+        for param in params {
+            self.add_local(param.get_name());
+        }
+
+        let contracts = contracts.cloned().unwrap_or_default();
+        if !contracts.requires.is_empty() {
+            let skip = self.emit(Op::JumpIfNoAssert(0));
+            for clause in &contracts.requires {
+                self.visit_expression(&clause.cond);
+                let condition = self.condition(clause);
+                self.emit(Op::Require(condition));
+            }
+            self.patch(skip);
+        }
+
+        // Postconditions are entered by `Return`, they see the parameters and
+        // `result` but not the locals of the body
+        if !contracts.ensures.is_empty() {
+            let skip = self.emit(Op::Jump(0));
+            let start = self.here();
+            self.fun().proto.ensures = Some(start);
+            self.add_local("result");
+            for clause in &contracts.ensures {
+                self.visit_expression(&clause.cond);
+                let condition = self.condition(clause);
+                self.emit(Op::Ensure(condition));
+            }
+            self.emit(Op::EndEnsures);
+            self.fun().locals.pop();
+            self.patch(skip);
+        }
+
+        for stmt in body {
+            self.visit_statement(stmt);
+        }
+        self.emit(Op::Nil);
+        self.emit(Op::RunDeferred(0));
+        self.emit(Op::Return);
+
+        let proto = self.funs.pop().unwrap().proto;
+        let protos = &mut self.chunk().protos;
+        protos.push(Rc::new(proto));
+        let index = protos.len() as u32 - 1;
+        self.emit(Op::Closure(index));
+    }
+
+    /// Compiles the value of a `return`, a call it ends with is a tail call
+    fn tail_expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                let (argc, paren) = self.callee_and_args(callee, paren, args);
+                self.emit(Op::TailCall(argc, paren));
+            }
+            Expr::Grouping(expr) => self.tail_expression(expr),
+            Expr::Logical { left, op, right } => {
+                let jump = self.short_circuit(left, op);
+                self.tail_expression(right);
+                self.patch(jump);
+            }
+            expr => self.visit_expression(expr),
+        }
+    }
+
+    /// Leaves the left operand of `and` or `or` if it decides the result,
+    /// returns the jump to patch past the right one
+    fn short_circuit(&mut self, left: &Expr, op: &TokenAstInfo) -> usize {
+        self.visit_expression(left);
+        self.line = op.line;
+        if op.kind == TokenType::Or {
+            self.emit(Op::JumpIfTrueOrPop(0))
+        } else {
+            self.emit(Op::JumpIfFalseOrPop(0))
+        }
+    }
+
+    fn callee_and_args(
+        &mut self,
+        callee: &Expr,
+        paren: &TokenAstInfo,
+        args: &[Expr],
+    ) -> (u32, u32) {
+        self.visit_expression(callee);
+        for arg in args {
+            self.visit_expression(arg);
+        }
+        (args.len() as u32, self.token(paren))
+    }
+}
+
+impl Visitor for Compiler {
+    type Output = ();
+
+    fn visit_statement(&mut self, stmt: &Stmt) -> Self::Output {
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.visit_expression(expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Print(exprs) => {
+                for expr in exprs {
+                    self.visit_expression(expr);
+                }
+                self.emit(Op::Print(exprs.len() as u32));
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                self.visit_expression(initializer);
+                if self.fun().scope_depth == 0 {
+                    let name = self.token(name);
+                    self.emit(Op::DefineGlobal(name));
+                } else {
+                    self.add_local(name.get_name());
+                }
+            }
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                for stmt in stmts {
+                    self.visit_statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::If {
+                cond,
+                then,
+                else_stmt,
+            } => {
+                self.visit_expression(cond);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.visit_statement(then);
+                match else_stmt {
+                    Some(else_stmt) => {
+                        let to_end = self.emit(Op::Jump(0));
+                        self.patch(to_else);
+                        self.visit_statement(else_stmt);
+                        self.patch(to_end);
+                    }
+                    None => self.patch(to_else),
+                }
+            }
+            Stmt::While {
+                cond,
+                body,
+                inc,
+                label,
+            } => {
+                let start = self.here();
+                self.visit_expression(cond);
+                let exit = self.emit(Op::JumpIfFalse(0));
+                let body = self.loop_body(label, body);
+                for at in body.continues {
+                    self.patch(at);
+                }
+                if let Some(inc) = inc {
+                    self.visit_expression(inc);
+                    self.emit(Op::Pop);
+                }
+                self.emit(Op::Jump(start));
+                self.patch(exit);
+                for at in body.breaks {
+                    self.patch(at);
+                }
+            }
+            Stmt::DoWhile { body, cond, label } => {
+                let start = self.here();
+                let body = self.loop_body(label, body);
+                for at in body.continues {
+                    self.patch(at);
+                }
+                self.visit_expression(cond);
+                self.emit(Op::JumpIfTrue(start));
+                for at in body.breaks {
+                    self.patch(at);
+                }
+            }
+            Stmt::Break { keyword, label } => {
+                self.line = keyword.line;
+                self.loop_exit(label, true);
+            }
+            Stmt::Continue { keyword, label } => {
+                self.line = keyword.line;
+                self.loop_exit(label, false);
+            }
+            Stmt::Function {
+                name,
+                params,
+                signature,
+                body,
+                is_async,
+                decorators,
+                contracts,
+            } => {
+                // A local function is bound to its slot before the closure is
+                // created, so it can capture itself
+                let is_local = self.fun().scope_depth > 0;
+                if is_local {
+                    self.emit(Op::Nil);
+                    self.add_local("");
+                }
+                for (_, decorator) in decorators {
+                    self.visit_expression(decorator);
+                }
+                if is_local {
+                    self.fun().locals.last_mut().unwrap().name = name.get_name().into();
+                }
+
+                self.line = name.line;
+                self.function(
+                    Some(name),
+                    params,
+                    signature,
+                    body,
+                    *is_async,
+                    Some(contracts),
+                );
+                // The decorator closest to the function is applied first
+                for (at, _) in decorators.iter().rev() {
+                    let at = self.token(at);
+                    self.emit(Op::Decorate(at));
+                }
+
+                if is_local {
+                    self.set_variable(name);
+                    self.emit(Op::Pop);
+                } else {
+                    let name = self.token(name);
+                    self.emit(Op::DefineGlobal(name));
+                }
+            }
+            Stmt::Return { keyword, val } => {
+                self.tail_expression(val);
+                self.line = keyword.line;
+                self.emit(Op::RunDeferred(0));
+                self.emit(Op::Return);
+            }
+            Stmt::Defer(expr) => {
+                let fun = self.fun();
+                if let Some(deferring) = fun.deferring.last_mut() {
+                    *deferring = true;
+                }
+                let depth = fun.scope_depth as u32;
+                let defer = self.emit(Op::Defer(0, depth));
+                self.visit_expression(expr);
+                self.emit(Op::Pop);
+                self.emit(Op::EndDefer);
+                self.patch(defer);
+            }
+            Stmt::Assert {
+                keyword,
+                cond,
+                msg,
+                source,
+            } => {
+                let skip = self.emit(Op::JumpIfNoAssert(0));
+                // Operands of a failed comparison are reported too, so they
+                // stay on the stack
+                let has_operands = match ungrouped(cond) {
+                    Expr::Binary { left, op, right } if is_comparison(op.kind) => {
+                        self.visit_expression(left);
+                        self.visit_expression(right);
+                        let op = self.token(op);
+                        self.emit(Op::BinaryKeep(op));
+                        true
+                    }
+                    cond => {
+                        self.visit_expression(cond);
+                        false
+                    }
+                };
+                let pass = self.emit(Op::JumpIfTrue(0));
+                if let Some(msg) = msg {
+                    self.visit_expression(msg);
+                }
+                let keyword = self.token(keyword);
+                let source = self.constant(Value::String(source.as_str().into()));
+                self.emit(Op::AssertFailed(
+                    keyword,
+                    source,
+                    msg.is_some(),
+                    has_operands,
+                ));
+                self.patch(pass);
+                if has_operands {
+                    self.pop(2);
+                }
+                self.patch(skip);
+            }
+        }
+    }
+
+    fn visit_expression(&mut self, expr: &Expr) -> Self::Output {
+        match expr {
+            Expr::Literal(val) => {
+                let op = match val {
+                    Value::Nil => Op::Nil,
+                    Value::Bool(true) => Op::True,
+                    Value::Bool(false) => Op::False,
+                    val => Op::Constant(self.constant(val.clone())),
+                };
+                self.emit(op);
+            }
+            Expr::Grouping(expr) => self.visit_expression(expr),
+            Expr::Unary { op, right } => {
+                self.visit_expression(right);
+                let op = match op.kind {
+                    TokenType::Minus => Op::Negate(self.token(op)),
+                    _ => Op::Not,
+                };
+                self.emit(op);
+            }
+            Expr::Binary { left, op, right } => {
+                self.visit_expression(left);
+                self.visit_expression(right);
+                let op = self.token(op);
+                self.emit(Op::Binary(op));
+            }
+            Expr::Variable { name, .. } => self.get_variable(name),
+            Expr::Assign { name, val, .. } => {
+                self.visit_expression(val);
+                self.set_variable(name);
+            }
+            Expr::Logical { left, op, right } => {
+                let jump = self.short_circuit(left, op);
+                self.visit_expression(right);
+                self.patch(jump);
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                let (argc, paren) = self.callee_and_args(callee, paren, args);
+                self.emit(Op::Call(argc, paren));
+            }
+            Expr::Lambda {
+                params,
+                signature,
+                body,
+                is_async,
+            } => self.function(None, params, signature, body, *is_async, None),
+            Expr::Tuple(items) => {
+                for item in items {
+                    self.visit_expression(item);
+                }
+                self.emit(Op::Tuple(items.len() as u32));
+            }
+            Expr::Set { brace, items } => {
+                let brace = self.token(brace);
+                self.emit(Op::Set);
+                for item in items {
+                    self.visit_expression(item);
+                    self.emit(Op::SetInsert(brace));
+                }
+            }
+            Expr::Record(fields) => {
+                for (_, val) in fields {
+                    self.visit_expression(val);
+                }
+                let first = self.chunk().tokens.len() as u32;
+                for (name, _) in fields {
+                    self.token(name);
+                }
+                self.emit(Op::Record(first, fields.len() as u32));
+            }
+            Expr::Get { object, name } => {
+                self.visit_expression(object);
+                let name = self.token(name);
+                self.emit(Op::GetField(name));
+            }
+            Expr::Await { keyword, expr } => {
+                self.visit_expression(expr);
+                let keyword = self.token(keyword);
+                self.emit(Op::Await(keyword));
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::Write;
+
+use crate::value::Value;
+
+use super::chunk::{Op, Proto};
+
+/// Lists the ops of a compiled script and of every function declared in it
+/// with the line they were compiled from. With the source at hand each line
+/// is also printed above its first op.
+pub fn disassemble(script: &Proto, source: Option<&str>) -> String {
+    let lines: Vec<_> = source.map(|s| s.lines().collect()).unwrap_or_default();
+    let mut out = String::new();
+    function(&mut out, "<script>", script, &lines);
+
+    out
+}
+
+fn title(proto: &Proto) -> String {
+    match &proto.name {
+        Some(name) => format!("<fun '{}'>", name.get_name()),
+        None => format!(
+            "<lambda at line {}>",
+            proto.chunk.lines.first().unwrap_or(&0)
+        ),
+    }
+}
+
+fn function(out: &mut String, heading: &str, proto: &Proto, source: &[&str]) {
+    let chunk = &proto.chunk;
+    writeln!(out, "== {heading} ==").unwrap();
+
+    let mut prev_line = None;
+    for (ip, (op, line)) in chunk.code.iter().zip(&chunk.lines).enumerate() {
+        let line_col = if prev_line == Some(*line) {
+            "|".to_string()
+        } else {
+            if let Some(text) = line.checked_sub(1).and_then(|i| source.get(i)) {
+                writeln!(out, "{:>9} | {}", line, text.trim()).unwrap();
+            }
+            line.to_string()
+        };
+        prev_line = Some(*line);
+
+        let op_text = format!("{op:?}");
+        match comment(proto, *op) {
+            Some(comment) => writeln!(out, "{ip:04} {line_col:>4}  {op_text:<24} {comment}"),
+            None => writeln!(out, "{ip:04} {line_col:>4}  {op_text}"),
+        }
+        .unwrap();
+    }
+
+    for proto in &chunk.protos {
+        out.push('\n');
+        function(out, &title(proto), proto, source);
+    }
+}
+
+/// What the operands of the op refer to
+fn comment(proto: &Proto, op: Op) -> Option<String> {
+    let chunk = &proto.chunk;
+    let token = |i: u32| &chunk.tokens[i as usize];
+    let constant = |i: u32| match &chunk.constants[i as usize] {
+        Value::String(s) => format!("{s:?}"),
+        val => val.to_string(),
+    };
+
+    Some(match op {
+        Op::Constant(i) => constant(i),
+        Op::GetGlobal(i) | Op::SetGlobal(i) | Op::DefineGlobal(i) | Op::GetField(i) => {
+            token(i).get_name().to_string()
+        }
+        Op::Binary(i) | Op::BinaryKeep(i) | Op::Negate(i) => token(i).kind.to_string(),
+        Op::Jump(t)
+        | Op::JumpIfFalse(t)
+        | Op::JumpIfTrue(t)
+        | Op::JumpIfFalseOrPop(t)
+        | Op::JumpIfTrueOrPop(t)
+        | Op::JumpIfNoAssert(t)
+        | Op::Defer(t, _) => format!("-> {t:04}"),
+        Op::Closure(i) => title(&chunk.protos[i as usize]),
+        Op::Record(first, n) => {
+            let names: Vec<_> = (first..first + n).map(|i| token(i).get_name()).collect();
+            names.join(", ")
+        }
+        Op::AssertFailed(_, source, ..) => constant(source),
+        Op::Require(i) | Op::Ensure(i) => proto.conditions[i as usize].source.clone(),
+        _ => return None,
+    })
+}
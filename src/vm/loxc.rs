@@ -0,0 +1,510 @@
+use std::{
+    io::{self, ErrorKind},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{
+        types::{Signature, Type, TypeAnn},
+        TokenAstInfo,
+    },
+    interner::intern,
+    tokentype::TokenType,
+    value::Value,
+};
+
+use super::{
+    chunk::{Capture, Chunk, Condition, Op, Proto},
+    verifier,
+};
+
+/// Starts every `.loxc` file
+const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped whenever the encoding of ops or prototypes changes, files of other
+/// versions are rejected
+pub const VERSION: u16 = 2;
+
+/// Serializes a compiled script. Integers are little-endian, strings and
+/// lists are prefixed with their length.
+pub fn encode(script: &Proto) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.buf.extend_from_slice(MAGIC);
+    writer.buf.extend_from_slice(&VERSION.to_le_bytes());
+    writer.proto(script);
+
+    writer.buf
+}
+
+/// Compiled scripts are recognized by their first bytes, whatever the file
+/// is named
+pub fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<Proto> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(invalid("Not a .loxc file".into()));
+    }
+
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(format!(
+            "Unsupported .loxc version {version}, expected {VERSION}"
+        )));
+    }
+
+    let script = reader.proto()?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("Trailing bytes after the script".into()));
+    }
+    verifier::verify(&script).map_err(invalid)?;
+
+    Ok(script)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u32(n as u32);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn option<T>(&mut self, val: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        match val {
+            Some(val) => {
+                self.u8(1);
+                write(self, val);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn token(&mut self, token: &TokenAstInfo) {
+        self.len(token.pos());
+        self.len(token.line);
+        self.u8(token.kind as u8);
+        self.option(&token.name, |w, name| w.str(name));
+    }
+
+    fn type_ann(&mut self, ann: &Option<TypeAnn>) {
+        self.option(ann, |w, ann| {
+            w.token(&ann.token);
+            w.str(&ann.tpe.to_string());
+        });
+    }
+
+    fn constant(&mut self, val: &Value) {
+        match val {
+            Value::Nil => self.u8(0),
+            Value::Bool(b) => {
+                self.u8(1);
+                self.bool(*b);
+            }
+            Value::Number(n) => {
+                self.u8(2);
+                self.buf.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::String(s) => {
+                self.u8(3);
+                self.str(s);
+            }
+            val => unreachable!(
+                "Well, that shouldn't happen... ICE Code: 0x9: {val} in a constant pool"
+            ),
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        let (tag, operands) = match op {
+            Op::Constant(i) => (0, vec![i]),
+            Op::Nil => (1, vec![]),
+            Op::True => (2, vec![]),
+            Op::False => (3, vec![]),
+            Op::Pop => (4, vec![]),
+            Op::PopN(n) => (5, vec![n]),
+            Op::Nop => (6, vec![]),
+            Op::GetLocal(i) => (7, vec![i]),
+            Op::SetLocal(i) => (8, vec![i]),
+            Op::MakeCell(i) => (9, vec![i]),
+            Op::GetUpvalue(i) => (10, vec![i]),
+            Op::SetUpvalue(i) => (11, vec![i]),
+            Op::GetGlobal(i) => (12, vec![i]),
+            Op::SetGlobal(i) => (13, vec![i]),
+            Op::DefineGlobal(i) => (14, vec![i]),
+            Op::Binary(i) => (15, vec![i]),
+            Op::BinaryKeep(i) => (16, vec![i]),
+            Op::Negate(i) => (17, vec![i]),
+            Op::Not => (18, vec![]),
+            Op::Jump(t) => (19, vec![t]),
+            Op::JumpIfFalse(t) => (20, vec![t]),
+            Op::JumpIfTrue(t) => (21, vec![t]),
+            Op::JumpIfFalseOrPop(t) => (22, vec![t]),
+            Op::JumpIfTrueOrPop(t) => (23, vec![t]),
+            Op::JumpIfNoAssert(t) => (24, vec![t]),
+            Op::Call(argc, paren) => (25, vec![argc, paren]),
+            Op::Closure(i) => (26, vec![i]),
+            Op::Return => (27, vec![]),
+            Op::Print(n) => (28, vec![n]),
+            Op::Tuple(n) => (29, vec![n]),
+            Op::Set => (30, vec![]),
+            Op::SetInsert(i) => (31, vec![i]),
+            Op::Record(first, n) => (32, vec![first, n]),
+            Op::GetField(i) => (33, vec![i]),
+            Op::Await(i) => (34, vec![i]),
+            Op::Decorate(i) => (35, vec![i]),
+            Op::Defer(t, depth) => (36, vec![t, depth]),
+            Op::RunDeferred(depth) => (37, vec![depth]),
+            Op::EndDefer => (38, vec![]),
+            Op::AssertFailed(keyword, source, has_msg, has_operands) => (
+                39,
+                vec![keyword, source, has_msg as u32, has_operands as u32],
+            ),
+            Op::Require(i) => (40, vec![i]),
+            Op::Ensure(i) => (41, vec![i]),
+            Op::EndEnsures => (42, vec![]),
+            Op::TailCall(argc, paren) => (43, vec![argc, paren]),
+        };
+
+        self.u8(tag);
+        for operand in operands {
+            self.u32(operand);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.len(chunk.code.len());
+        for (op, line) in chunk.code.iter().zip(&chunk.lines) {
+            self.op(*op);
+            self.len(*line);
+        }
+
+        self.len(chunk.constants.len());
+        for val in &chunk.constants {
+            self.constant(val);
+        }
+
+        self.len(chunk.tokens.len());
+        for token in &chunk.tokens {
+            self.token(token);
+        }
+
+        self.len(chunk.protos.len());
+        for proto in &chunk.protos {
+            self.proto(proto);
+        }
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.option(&proto.name, |w, name| w.token(name));
+
+        self.len(proto.params.len());
+        for (param, ann) in proto.params.iter().zip(&proto.signature.params) {
+            self.token(param);
+            self.type_ann(ann);
+        }
+        self.type_ann(&proto.signature.ret);
+        self.bool(proto.is_async);
+
+        self.len(proto.captures.len());
+        for capture in &proto.captures {
+            self.bool(capture.is_local);
+            self.u32(capture.index);
+        }
+
+        self.len(proto.conditions.len());
+        for condition in &proto.conditions {
+            self.token(&condition.keyword);
+            self.str(&condition.source);
+        }
+        self.option(&proto.ensures, |w, start| w.u32(*start));
+
+        self.chunk(&proto.chunk);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("Truncated .loxc file".into()))?;
+        self.pos += n;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(invalid(format!("Invalid boolean {b}"))),
+        }
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| invalid("Invalid UTF-8 in a string".into()))
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        match self.bool()? {
+            true => Ok(Some(read(self)?)),
+            false => Ok(None),
+        }
+    }
+
+    fn token(&mut self) -> io::Result<TokenAstInfo> {
+        let pos = self.len()?;
+        let line = self.len()?;
+        let kind = self.u8()?;
+        let kind = *TokenType::ALL
+            .get(kind as usize)
+            .ok_or_else(|| invalid(format!("Invalid token type {kind}")))?;
+        let name = self.option(|r| r.str().map(|s| intern(&s)))?;
+
+        Ok(TokenAstInfo::new(pos, line, kind, name))
+    }
+
+    fn type_ann(&mut self) -> io::Result<Option<TypeAnn>> {
+        self.option(|r| {
+            let token = r.token()?;
+            let name = r.str()?;
+            let tpe =
+                Type::from_name(&name).ok_or_else(|| invalid(format!("Unknown type '{name}'")))?;
+
+            Ok(TypeAnn { token, tpe })
+        })
+    }
+
+    fn constant(&mut self) -> io::Result<Value> {
+        Ok(match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Bool(self.bool()?),
+            2 => Value::Number(f64::from_bits(u64::from_le_bytes(
+                self.take(8)?.try_into().unwrap(),
+            ))),
+            3 => Value::String(intern(&self.str()?)),
+            tag => return Err(invalid(format!("Invalid constant tag {tag}"))),
+        })
+    }
+
+    fn op(&mut self) -> io::Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Constant(self.u32()?),
+            1 => Op::Nil,
+            2 => Op::True,
+            3 => Op::False,
+            4 => Op::Pop,
+            5 => Op::PopN(self.u32()?),
+            6 => Op::Nop,
+            7 => Op::GetLocal(self.u32()?),
+            8 => Op::SetLocal(self.u32()?),
+            9 => Op::MakeCell(self.u32()?),
+            10 => Op::GetUpvalue(self.u32()?),
+            11 => Op::SetUpvalue(self.u32()?),
+            12 => Op::GetGlobal(self.u32()?),
+            13 => Op::SetGlobal(self.u32()?),
+            14 => Op::DefineGlobal(self.u32()?),
+            15 => Op::Binary(self.u32()?),
+            16 => Op::BinaryKeep(self.u32()?),
+            17 => Op::Negate(self.u32()?),
+            18 => Op::Not,
+            19 => Op::Jump(self.u32()?),
+            20 => Op::JumpIfFalse(self.u32()?),
+            21 => Op::JumpIfTrue(self.u32()?),
+            22 => Op::JumpIfFalseOrPop(self.u32()?),
+            23 => Op::JumpIfTrueOrPop(self.u32()?),
+            24 => Op::JumpIfNoAssert(self.u32()?),
+            25 => Op::Call(self.u32()?, self.u32()?),
+            26 => Op::Closure(self.u32()?),
+            27 => Op::Return,
+            28 => Op::Print(self.u32()?),
+            29 => Op::Tuple(self.u32()?),
+            30 => Op::Set,
+            31 => Op::SetInsert(self.u32()?),
+            32 => Op::Record(self.u32()?, self.u32()?),
+            33 => Op::GetField(self.u32()?),
+            34 => Op::Await(self.u32()?),
+            35 => Op::Decorate(self.u32()?),
+            36 => Op::Defer(self.u32()?, self.u32()?),
+            37 => Op::RunDeferred(self.u32()?),
+            38 => Op::EndDefer,
+            39 => Op::AssertFailed(self.u32()?, self.u32()?, self.u32()? != 0, self.u32()? != 0),
+            40 => Op::Require(self.u32()?),
+            41 => Op::Ensure(self.u32()?),
+            42 => Op::EndEnsures,
+            43 => Op::TailCall(self.u32()?, self.u32()?),
+            tag => return Err(invalid(format!("Invalid op tag {tag}"))),
+        })
+    }
+
+    fn chunk(&mut self) -> io::Result<Chunk> {
+        let mut chunk = Chunk::default();
+        for _ in 0..self.len()? {
+            let op = self.op()?;
+            let line = self.len()?;
+            chunk.write(op, line);
+        }
+
+        for _ in 0..self.len()? {
+            chunk.constants.push(self.constant()?);
+        }
+
+        for _ in 0..self.len()? {
+            chunk.tokens.push(self.token()?);
+        }
+
+        for _ in 0..self.len()? {
+            chunk.protos.push(Rc::new(self.proto()?));
+        }
+
+        Ok(chunk)
+    }
+
+    fn proto(&mut self) -> io::Result<Proto> {
+        let name = self.option(Self::token)?;
+
+        let mut params = vec![];
+        let mut signature = Signature::default();
+        for _ in 0..self.len()? {
+            params.push(self.token()?);
+            signature.params.push(self.type_ann()?);
+        }
+        signature.ret = self.type_ann()?;
+        let is_async = self.bool()?;
+
+        let mut captures = vec![];
+        for _ in 0..self.len()? {
+            captures.push(Capture {
+                is_local: self.bool()?,
+                index: self.u32()?,
+            });
+        }
+
+        let mut conditions = vec![];
+        for _ in 0..self.len()? {
+            conditions.push(Condition {
+                keyword: self.token()?,
+                source: self.str()?,
+            });
+        }
+        let ensures = self.option(Self::u32)?;
+
+        let proto = Proto {
+            name,
+            params,
+            signature,
+            is_async,
+            captures,
+            conditions,
+            ensures,
+            chunk: self.chunk()?,
+        };
+        validate(&proto)?;
+
+        Ok(proto)
+    }
+}
+
+/// Checks that every operand refers to an existing entry of its table, so a
+/// corrupt file fails to load instead of crashing the VM
+fn validate(proto: &Proto) -> io::Result<()> {
+    let chunk = &proto.chunk;
+    let in_range = |i: u32, len: usize, what: &str| {
+        if (i as usize) < len {
+            Ok(())
+        } else {
+            Err(invalid(format!("{what} {i} out of range")))
+        }
+    };
+    let constant = |i| in_range(i, chunk.constants.len(), "Constant");
+    let token = |i| in_range(i, chunk.tokens.len(), "Token");
+    let target = |i| in_range(i, chunk.code.len(), "Jump target");
+
+    if let Some(start) = proto.ensures {
+        target(start)?;
+    }
+
+    for op in &chunk.code {
+        match *op {
+            Op::Constant(i) => constant(i)?,
+            Op::GetGlobal(i)
+            | Op::SetGlobal(i)
+            | Op::DefineGlobal(i)
+            | Op::Binary(i)
+            | Op::BinaryKeep(i)
+            | Op::Negate(i)
+            | Op::Call(_, i)
+            | Op::TailCall(_, i)
+            | Op::SetInsert(i)
+            | Op::GetField(i)
+            | Op::Await(i)
+            | Op::Decorate(i) => token(i)?,
+            Op::Record(first, n) if n > 0 => token(first.saturating_add(n - 1))?,
+            Op::Jump(t)
+            | Op::JumpIfFalse(t)
+            | Op::JumpIfTrue(t)
+            | Op::JumpIfFalseOrPop(t)
+            | Op::JumpIfTrueOrPop(t)
+            | Op::JumpIfNoAssert(t)
+            | Op::Defer(t, _) => target(t)?,
+            Op::Closure(i) => in_range(i, chunk.protos.len(), "Function")?,
+            Op::GetUpvalue(i) | Op::SetUpvalue(i) => in_range(i, proto.captures.len(), "Upvalue")?,
+            Op::AssertFailed(keyword, source, ..) => {
+                token(keyword)?;
+                constant(source)?;
+            }
+            Op::Require(i) | Op::Ensure(i) => in_range(i, proto.conditions.len(), "Condition")?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
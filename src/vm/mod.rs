@@ -0,0 +1,582 @@
+pub mod chunk;
+pub mod compiler;
+pub mod disassembler;
+pub mod loxc;
+mod verifier;
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::TokenAstInfo,
+    error::{Error, RuntimeError},
+    fiber,
+    function::{check_param, check_type, contract_violation, Function},
+    interpreter::{assert_failure, check_set_element, get_field, is_truthy, Interpreter},
+    lox_callable::Callable,
+    value::{Record, Set, Value},
+};
+
+use chunk::{Chunk, Closure, Op, Proto};
+
+/// Stack entry. Locals captured by closures are moved into cells shared with
+/// them.
+#[derive(Debug)]
+enum Slot {
+    Value(Value),
+    Cell(Rc<RefCell<Value>>),
+}
+
+impl Slot {
+    fn get(&self) -> Value {
+        match self {
+            Slot::Value(val) => val.clone(),
+            Slot::Cell(cell) => cell.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, val: Value) {
+        match self {
+            Slot::Value(old) => *old = val,
+            Slot::Cell(cell) => *cell.borrow_mut() = val,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Slot::Value(val) => val,
+            Slot::Cell(cell) => cell.borrow().clone(),
+        }
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Stack index of the callee, the first slot of the frame
+    base: usize,
+    /// Closing parenthesis of the call, broken preconditions and parameter
+    /// types are reported there. `None` for scripts.
+    paren: Option<TokenAstInfo>,
+    /// Block depth and start of every registered deferred expression
+    deferred: Vec<(usize, usize)>,
+    /// Runs a deferred expression with the slots of the frame below it
+    thunk: bool,
+    /// Pushed by a call counted in the call depth of the interpreter
+    counted: bool,
+}
+
+impl Frame {
+    fn new(closure: Rc<Closure>, base: usize, paren: Option<TokenAstInfo>) -> Self {
+        Self {
+            closure,
+            ip: 0,
+            base,
+            paren,
+            deferred: vec![],
+            thunk: false,
+            counted: false,
+        }
+    }
+
+    fn thunk(&self, ip: usize) -> Self {
+        Self {
+            closure: Rc::clone(&self.closure),
+            ip,
+            base: self.base,
+            paren: self.paren.clone(),
+            deferred: vec![],
+            thunk: true,
+            counted: false,
+        }
+    }
+}
+
+/// Stack machine running compiled functions. Lox calls push frames instead of
+/// recursing, a new VM is only started when a native or the event loop calls
+/// back into Lox.
+struct Vm<'a> {
+    interpreter: &'a mut Interpreter,
+    stack: Vec<Slot>,
+    frames: Vec<Frame>,
+}
+
+/// Runs a compiled script in the calling fiber
+pub fn run_script(interpreter: &mut Interpreter, script: Proto) -> Result<Value, Error> {
+    let closure = Rc::new(Closure {
+        proto: Rc::new(script),
+        upvalues: vec![],
+    });
+
+    let mut vm = Vm::new(interpreter);
+    vm.push(Value::Fun(Function::Closure(Rc::clone(&closure))));
+    vm.frames.push(Frame::new(closure, 0, None));
+    vm.run(0)
+}
+
+/// Runs the body of a compiled function, its arguments were already checked
+pub fn call(
+    interpreter: &mut Interpreter,
+    closure: &Rc<Closure>,
+    paren: &TokenAstInfo,
+    args: &[Value],
+) -> Result<Value, Error> {
+    let mut vm = Vm::new(interpreter);
+    vm.push(Value::Fun(Function::Closure(Rc::clone(closure))));
+    for arg in args {
+        vm.push(arg.clone());
+    }
+    vm.frames
+        .push(Frame::new(Rc::clone(closure), 0, Some(paren.clone())));
+    vm.run(0)
+}
+
+impl<'a> Vm<'a> {
+    fn new(interpreter: &'a mut Interpreter) -> Self {
+        Self {
+            interpreter,
+            stack: vec![],
+            frames: vec![],
+        }
+    }
+
+    fn push(&mut self, val: Value) {
+        self.stack.push(Slot::Value(val));
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap().into_value()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance].get()
+    }
+
+    /// Removes the top `count` values
+    fn pop_many(&mut self, count: usize) -> Vec<Value> {
+        let at = self.stack.len() - count;
+        self.stack.drain(at..).map(Slot::into_value).collect()
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frames.last().unwrap().closure.proto.chunk
+    }
+
+    fn token(&self, index: u32) -> &TokenAstInfo {
+        &self.chunk().tokens[index as usize]
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frame().ip = target as usize;
+    }
+
+    /// Runs until the frame count drops to `stop_depth`, returning the value
+    /// of the last frame left. Frames left by an error run their deferred
+    /// expressions first.
+    fn run(&mut self, stop_depth: usize) -> Result<Value, Error> {
+        let res = self.execute(stop_depth);
+        if res.is_err() {
+            self.unwind(stop_depth);
+        }
+
+        res
+    }
+
+    fn unwind(&mut self, stop_depth: usize) {
+        while self.frames.len() > stop_depth {
+            let frame = self.frame();
+            if let Some((_, ip)) = frame.deferred.pop() {
+                let thunk = frame.thunk(ip);
+                self.frames.push(thunk);
+                // Errors of deferred expressions don't replace the one
+                // already unwinding
+                let _ = self.run(self.frames.len() - 1);
+            } else {
+                let frame = self.frames.pop().unwrap();
+                if !frame.thunk {
+                    self.stack.truncate(frame.base);
+                }
+                if frame.counted {
+                    self.interpreter.exit_call();
+                }
+            }
+        }
+    }
+
+    /// Pops the current frame. Returns the value if it was the last one to
+    /// run, otherwise hands it to the caller.
+    fn leave(&mut self, val: Value, stop_depth: usize) -> Option<Value> {
+        let frame = self.frames.pop().unwrap();
+        self.stack.truncate(frame.base);
+        if frame.counted {
+            self.interpreter.exit_call();
+        }
+        if self.frames.len() == stop_depth {
+            Some(val)
+        } else {
+            self.push(val);
+            None
+        }
+    }
+
+    fn execute(&mut self, stop_depth: usize) -> Result<Value, Error> {
+        loop {
+            self.interpreter.step()?;
+            let frame = self.frame();
+            let op = frame.closure.proto.chunk.code[frame.ip];
+            frame.ip += 1;
+            let base = frame.base;
+
+            match op {
+                Op::Constant(i) => {
+                    let val = self.chunk().constants[i as usize].clone();
+                    self.push(val);
+                }
+                Op::Nil => self.push(Value::Nil),
+                Op::True => self.push(Value::Bool(true)),
+                Op::False => self.push(Value::Bool(false)),
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::PopN(count) => {
+                    self.stack.truncate(self.stack.len() - count as usize);
+                }
+                Op::Nop => (),
+                Op::GetLocal(slot) => {
+                    let val = self.stack[base + slot as usize].get();
+                    self.push(val);
+                }
+                Op::SetLocal(slot) => {
+                    let val = self.peek(0);
+                    self.stack[base + slot as usize].set(val);
+                }
+                Op::MakeCell(slot) => {
+                    let slot = &mut self.stack[base + slot as usize];
+                    let cell = Rc::new(RefCell::new(slot.get()));
+                    *slot = Slot::Cell(Rc::clone(&cell));
+                    self.interpreter.collector().borrow_mut().track_cell(&cell);
+                }
+                Op::GetUpvalue(i) => {
+                    let val = self.frame().closure.upvalues[i as usize].borrow().clone();
+                    self.push(val);
+                }
+                Op::SetUpvalue(i) => {
+                    let val = self.peek(0);
+                    *self.frame().closure.upvalues[i as usize].borrow_mut() = val;
+                }
+                Op::GetGlobal(name) => {
+                    let val = self.interpreter.globals.borrow().get(self.token(name))?;
+                    self.push(val);
+                }
+                Op::SetGlobal(name) => {
+                    let val = self.peek(0);
+                    self.interpreter
+                        .globals
+                        .borrow_mut()
+                        .assign(self.token(name), &val)?;
+                }
+                Op::DefineGlobal(name) => {
+                    let val = self.pop();
+                    self.interpreter
+                        .globals
+                        .borrow_mut()
+                        .define(self.token(name), val);
+                }
+                Op::Binary(op) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let val = self.interpreter.binary(left, self.token(op), right)?;
+                    self.push(val);
+                }
+                Op::BinaryKeep(op) => {
+                    let (left, right) = (self.peek(1), self.peek(0));
+                    let val = self.interpreter.binary(left, self.token(op), right)?;
+                    self.push(val);
+                }
+                Op::Negate(op) => match self.pop() {
+                    Value::Number(n) => self.push(Value::Number(-n)),
+                    _ => return Err(RuntimeError::number_op_err(self.token(op)).into()),
+                },
+                Op::Not => {
+                    let val = self.pop();
+                    self.push(Value::Bool(!is_truthy(&val)));
+                }
+                Op::Jump(target) => self.jump(target),
+                Op::JumpIfFalse(target) => {
+                    if !is_truthy(&self.pop()) {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfTrue(target) => {
+                    if is_truthy(&self.pop()) {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfFalseOrPop(target) => {
+                    if is_truthy(&self.peek(0)) {
+                        self.stack.pop();
+                    } else {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfTrueOrPop(target) => {
+                    if is_truthy(&self.peek(0)) {
+                        self.jump(target);
+                    } else {
+                        self.stack.pop();
+                    }
+                }
+                Op::JumpIfNoAssert(target) => {
+                    if !self.interpreter.assertions() {
+                        self.jump(target);
+                    }
+                }
+                Op::Call(argc, paren) => self.call_value(argc as usize, paren, false)?,
+                Op::TailCall(argc, paren) => {
+                    let frame = self.frames.last().unwrap();
+                    let proto = &frame.closure.proto;
+                    // Results that are checked can't be left to a tail call
+                    let checked = proto.ensures.is_some() && self.interpreter.assertions()
+                        || proto.signature.ret.is_some() && self.interpreter.type_hints();
+                    let tail = !checked && frame.deferred.is_empty();
+                    self.call_value(argc as usize, paren, tail)?
+                }
+                Op::Closure(i) => {
+                    let frame = self.frames.last().unwrap();
+                    let proto = Rc::clone(&frame.closure.proto.chunk.protos[i as usize]);
+                    let mut upvalues = Vec::with_capacity(proto.captures.len());
+                    for capture in &proto.captures {
+                        let index = capture.index as usize;
+                        if !capture.is_local {
+                            upvalues.push(Rc::clone(&frame.closure.upvalues[index]));
+                            continue;
+                        }
+                        let slot = &mut self.stack[frame.base + index];
+                        let cell = match slot {
+                            Slot::Cell(cell) => Rc::clone(cell),
+                            // The compiler makes captured locals cells first,
+                            // bytecode loaded from a file might not
+                            Slot::Value(val) => {
+                                let cell = Rc::new(RefCell::new(val.clone()));
+                                *slot = Slot::Cell(Rc::clone(&cell));
+                                self.interpreter.collector().borrow_mut().track_cell(&cell);
+                                cell
+                            }
+                        };
+                        upvalues.push(cell);
+                    }
+                    let closure = Closure { proto, upvalues };
+                    self.push(Value::Fun(Function::Closure(Rc::new(closure))));
+                }
+                Op::Return => {
+                    let val = self.pop();
+                    let frame = self.frames.last().unwrap();
+                    let proto = &frame.closure.proto;
+                    if let (true, Some(paren)) = (self.interpreter.type_hints(), &frame.paren) {
+                        check_type(paren, &proto.signature.ret, &val, || {
+                            "as return value".into()
+                        })?;
+                    }
+
+                    let ensures = proto.ensures.filter(|_| self.interpreter.assertions());
+                    let params_end = base + 1 + proto.arity();
+                    if let Some(ensures) = ensures {
+                        // `result` takes the slot after the parameters
+                        self.stack.truncate(params_end);
+                        self.push(val);
+                        self.jump(ensures);
+                    } else if let Some(val) = self.leave(val, stop_depth) {
+                        return Ok(val);
+                    }
+                }
+                Op::EndEnsures => {
+                    let val = self.pop();
+                    if let Some(val) = self.leave(val, stop_depth) {
+                        return Ok(val);
+                    }
+                }
+                Op::Print(count) => {
+                    let mut res = String::new();
+                    for val in self.pop_many(count as usize) {
+                        res += &val.to_string();
+                    }
+                    println!("{res}")
+                }
+                Op::Tuple(count) => {
+                    let items = self.pop_many(count as usize);
+                    self.push(Value::Tuple(items.into()));
+                }
+                Op::Set => self.push(Value::Set(Rc::new(Set::default()))),
+                Op::SetInsert(brace) => {
+                    let item = self.pop();
+                    check_set_element(self.token(brace), &item)?;
+                    if let Some(Slot::Value(Value::Set(set))) = self.stack.last_mut() {
+                        Rc::make_mut(set).insert(item);
+                    }
+                }
+                Op::Record(first, count) => {
+                    let values = self.pop_many(count as usize);
+                    let names = &self.chunk().tokens[first as usize..];
+                    let fields = names
+                        .iter()
+                        .zip(values)
+                        .map(|(name, val)| (Rc::clone(name.interned_name()), val))
+                        .collect();
+                    self.push(Value::Record(Rc::new(Record::new(fields))));
+                }
+                Op::GetField(name) => {
+                    let object = self.pop();
+                    let val = get_field(&object, self.token(name))?;
+                    self.push(val);
+                }
+                Op::Await(keyword) => {
+                    let mut val = self.pop();
+                    // A promise resolved with another promise waits for that one too
+                    while let Value::Promise(promise) = val {
+                        val =
+                            fiber::await_promise(self.interpreter, self.token(keyword), &promise)?;
+                    }
+                    self.push(val);
+                }
+                Op::Decorate(at) => {
+                    let fun = self.pop();
+                    let decorator = self.pop();
+                    let at = self.token(at).clone();
+                    let val = self.interpreter.decorate(&at, decorator, fun)?;
+                    self.push(val);
+                }
+                Op::Defer(skip, depth) => {
+                    let frame = self.frame();
+                    frame.deferred.push((depth as usize, frame.ip));
+                    frame.ip = skip as usize;
+                }
+                Op::RunDeferred(depth) => {
+                    // Runs one deferred expression, then this op again
+                    let frame = self.frame();
+                    match frame.deferred.last() {
+                        Some(&(at, ip)) if at >= depth as usize => {
+                            frame.deferred.pop();
+                            frame.ip -= 1;
+                            let thunk = frame.thunk(ip);
+                            self.frames.push(thunk);
+                        }
+                        _ => (),
+                    }
+                }
+                Op::EndDefer => {
+                    self.frames.pop();
+                    if self.frames.len() == stop_depth {
+                        return Ok(Value::Nil);
+                    }
+                }
+                Op::AssertFailed(keyword, source, has_msg, has_operands) => {
+                    let msg = has_msg.then(|| self.pop());
+                    let operands = has_operands.then(|| {
+                        let right = self.pop();
+                        (self.pop(), right)
+                    });
+                    let source = self.chunk().constants[source as usize].to_string();
+                    return Err(assert_failure(self.token(keyword), &source, msg, operands));
+                }
+                Op::Require(i) => {
+                    if !is_truthy(&self.pop()) {
+                        let frame = self.frames.last().unwrap();
+                        let proto = &frame.closure.proto;
+                        let paren = frame.paren.as_ref().unwrap();
+                        // Callers are to blame for broken preconditions
+                        return Err(contract_violation(
+                            paren,
+                            "Precondition",
+                            proto.name.as_ref().unwrap().get_name(),
+                            &proto.conditions[i as usize].source,
+                        ));
+                    }
+                }
+                Op::Ensure(i) => {
+                    if !is_truthy(&self.pop()) {
+                        let proto = &self.frames.last().unwrap().closure.proto;
+                        let condition = &proto.conditions[i as usize];
+                        return Err(contract_violation(
+                            &condition.keyword,
+                            "Postcondition",
+                            proto.name.as_ref().unwrap().get_name(),
+                            &condition.source,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls the value below the arguments. Synchronous Lox functions get a new
+    /// frame, everything else is called directly.
+    /// A tail call replaces the frame of the caller, the callee and arguments
+    /// take its slots
+    fn call_value(&mut self, argc: usize, paren: u32, tail: bool) -> Result<(), Error> {
+        let at = self.stack.len() - argc - 1;
+        let Value::Fun(fun) = self.stack[at].get() else {
+            return Err(RuntimeError {
+                token: self.token(paren).clone(),
+                msg: "Can only call functions and classes".into(),
+            }
+            .into());
+        };
+
+        if argc != fun.arity() {
+            return Err(RuntimeError {
+                token: self.token(paren).clone(),
+                msg: format!("Expected {} arguments but got {}", fun.arity(), argc),
+            }
+            .into());
+        }
+
+        let paren = self.token(paren).clone();
+        // Tail calls take the place of the call they return from
+        if !tail {
+            self.interpreter.enter_call(&paren)?;
+        }
+        match fun {
+            Function::Closure(closure) if !closure.proto.is_async => {
+                if self.interpreter.type_hints() {
+                    let proto = &closure.proto;
+                    let args = &self.stack[at + 1..];
+                    for ((param, tpe), arg) in
+                        proto.params.iter().zip(&proto.signature.params).zip(args)
+                    {
+                        if let Err(e) = check_param(&paren, param, tpe, &arg.get()) {
+                            if !tail {
+                                self.interpreter.exit_call();
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                if tail {
+                    let frame = self.frames.last_mut().unwrap();
+                    let (base, counted) = (frame.base, frame.counted);
+                    *frame = Frame::new(closure, base, Some(paren));
+                    frame.counted = counted;
+                    self.stack.drain(base..at);
+                } else {
+                    let mut frame = Frame::new(closure, at, Some(paren));
+                    frame.counted = true;
+                    self.frames.push(frame);
+                }
+            }
+            fun => {
+                let args = self.pop_many(argc);
+                self.stack.pop();
+                let res = fun.call(self.interpreter, &paren, &args);
+                if !tail {
+                    self.interpreter.exit_call();
+                }
+                self.push(res?);
+            }
+        }
+
+        Ok(())
+    }
+}
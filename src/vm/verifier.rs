@@ -0,0 +1,324 @@
+use crate::{ast::TokenAstInfo, tokentype::TokenType};
+
+use super::chunk::{Op, Proto};
+
+/// Operators `Binary` can evaluate
+const BINARY_OPERATORS: [TokenType; 12] = [
+    TokenType::Minus,
+    TokenType::Slash,
+    TokenType::Star,
+    TokenType::Plus,
+    TokenType::Greater,
+    TokenType::GreaterEq,
+    TokenType::Less,
+    TokenType::LessEq,
+    TokenType::BangEq,
+    TokenType::EqEq,
+    TokenType::Percent,
+    TokenType::In,
+];
+
+/// Where an op runs: in the frame of its function, or in the thunk of a
+/// deferred expression entered with the given stack height
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    Frame,
+    Thunk(usize),
+}
+
+/// Stack height relative to the frame base before an op runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    height: usize,
+    context: Context,
+}
+
+/// Checks that a script loaded from a `.loxc` file can't take the VM out of
+/// its tables or its stack. Every op must be reached with the same stack
+/// height from all of its predecessors, as the compiler guarantees, so the
+/// height of each one is known.
+pub fn verify(script: &Proto) -> Result<(), String> {
+    if script.arity() > 0 {
+        return Err("Script with parameters".into());
+    }
+
+    Verifier::new(script, true).run()
+}
+
+struct Verifier<'a> {
+    proto: &'a Proto,
+    /// Scripts run without a call, their frame has no parenthesis to blame
+    /// for broken preconditions
+    is_script: bool,
+    /// State of every op reached so far
+    states: Vec<Option<State>>,
+    pending: Vec<usize>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(proto: &'a Proto, is_script: bool) -> Self {
+        Self {
+            proto,
+            is_script,
+            states: vec![None; proto.chunk.code.len()],
+            pending: vec![],
+        }
+    }
+
+    fn run(mut self) -> Result<(), String> {
+        let proto = self.proto;
+        let unnamed = |token: &TokenAstInfo| token.name.is_none();
+        if proto.name.as_ref().is_some_and(unnamed) || proto.params.iter().any(unnamed) {
+            return Err("Unnamed function or parameter".into());
+        }
+        for child in &proto.chunk.protos {
+            Verifier::new(child, false).run()?;
+        }
+
+        // The callee and the parameters
+        let floor = 1 + proto.arity();
+        let frame = |height| State {
+            height,
+            context: Context::Frame,
+        };
+        self.enter(0, frame(floor))?;
+        if let Some(start) = proto.ensures {
+            // `Return` leaves the result after the parameters
+            self.enter(start as usize, frame(floor + 1))?;
+        }
+
+        while let Some(ip) = self.pending.pop() {
+            self.step(ip).map_err(|msg| {
+                let name = proto.name.as_ref().map_or("script", |name| name.get_name());
+                format!("{msg} at op {ip} of {name}")
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the state `ip` is reached with
+    fn enter(&mut self, ip: usize, state: State) -> Result<(), String> {
+        match self.states.get(ip) {
+            None => Err("Jump out of the code".into()),
+            Some(None) => {
+                self.states[ip] = Some(state);
+                self.pending.push(ip);
+                Ok(())
+            }
+            Some(Some(known)) if *known == state => Ok(()),
+            Some(Some(_)) => Err("Inconsistent stack height".into()),
+        }
+    }
+
+    fn step(&mut self, ip: usize) -> Result<(), String> {
+        let State {
+            mut height,
+            context,
+        } = self.states[ip].unwrap();
+        let proto = self.proto;
+        let chunk = &proto.chunk;
+        let in_frame = context == Context::Frame;
+        // Values below are the parameters, or those of the deferring frame
+        let floor = match context {
+            Context::Frame => 1 + proto.arity(),
+            Context::Thunk(entry) => entry,
+        };
+        let mut pop = |n: u32| match height.checked_sub(n as usize) {
+            Some(left) if left >= floor => {
+                height = left;
+                Ok(())
+            }
+            _ => Err(String::from("Stack underflow")),
+        };
+        let state = |height| State { height, context };
+
+        match chunk.code[ip] {
+            Op::Constant(i) => {
+                self.constant(i)?;
+                height += 1;
+            }
+            Op::Nil | Op::True | Op::False | Op::Set => height += 1,
+            Op::Pop => pop(1)?,
+            Op::PopN(count) => pop(count)?,
+            Op::Nop | Op::RunDeferred(_) => (),
+            Op::GetLocal(slot) => {
+                self.slot(slot, height)?;
+                height += 1;
+            }
+            Op::SetLocal(slot) => {
+                pop(1)?;
+                height += 1;
+                self.slot(slot, height)?;
+            }
+            Op::MakeCell(slot) => self.slot(slot, height)?,
+            Op::GetUpvalue(i) => {
+                self.upvalue(i)?;
+                height += 1;
+            }
+            Op::SetUpvalue(i) => {
+                self.upvalue(i)?;
+                pop(1)?;
+                height += 1;
+            }
+            Op::GetGlobal(name) => {
+                self.name(name)?;
+                height += 1;
+            }
+            Op::SetGlobal(name) | Op::GetField(name) => {
+                self.name(name)?;
+                pop(1)?;
+                height += 1;
+            }
+            Op::DefineGlobal(name) => {
+                self.name(name)?;
+                pop(1)?;
+            }
+            Op::Binary(op) | Op::BinaryKeep(op) => {
+                if !BINARY_OPERATORS.contains(&self.token(op)?.kind) {
+                    return Err("Invalid binary operator".into());
+                }
+                pop(2)?;
+                height += if let Op::Binary(_) = chunk.code[ip] {
+                    1
+                } else {
+                    3
+                };
+            }
+            Op::Negate(token) | Op::Await(token) | Op::SetInsert(token) => {
+                self.token(token)?;
+                pop(1)?;
+                if !matches!(chunk.code[ip], Op::SetInsert(_)) {
+                    height += 1;
+                }
+            }
+            Op::Not => {
+                pop(1)?;
+                height += 1;
+            }
+            Op::Jump(target) => return self.enter(target as usize, state(height)),
+            Op::JumpIfFalse(target) | Op::JumpIfTrue(target) => {
+                pop(1)?;
+                self.enter(target as usize, state(height))?;
+            }
+            Op::JumpIfFalseOrPop(target) | Op::JumpIfTrueOrPop(target) => {
+                pop(1)?;
+                self.enter(target as usize, state(height + 1))?;
+            }
+            Op::JumpIfNoAssert(target) => self.enter(target as usize, state(height))?,
+            Op::Call(argc, paren) | Op::TailCall(argc, paren) => {
+                if !in_frame && matches!(chunk.code[ip], Op::TailCall(..)) {
+                    return Err("Tail call in a deferred expression".into());
+                }
+                self.token(paren)?;
+                pop(argc.saturating_add(1))?;
+                height += 1;
+            }
+            Op::Closure(i) => {
+                let child = (chunk.protos)
+                    .get(i as usize)
+                    .ok_or("Invalid function index")?;
+                for capture in &child.captures {
+                    if capture.is_local {
+                        self.slot(capture.index, height)?;
+                    } else {
+                        self.upvalue(capture.index)?;
+                    }
+                }
+                height += 1;
+            }
+            Op::Return | Op::EndEnsures => {
+                if !in_frame {
+                    return Err("Return from a deferred expression".into());
+                }
+                // Postconditions were entered along with the first op
+                return pop(1);
+            }
+            Op::Print(count) => pop(count)?,
+            Op::Tuple(count) => {
+                pop(count)?;
+                height += 1;
+            }
+            Op::Record(first, count) => {
+                for name in first..first.saturating_add(count) {
+                    self.name(name)?;
+                }
+                pop(count)?;
+                height += 1;
+            }
+            Op::Decorate(at) => {
+                self.token(at)?;
+                pop(2)?;
+                height += 1;
+            }
+            Op::Defer(skip, _) => {
+                if !in_frame || skip as usize <= ip {
+                    return Err("Invalid deferred expression".into());
+                }
+                self.enter(skip as usize, state(height))?;
+                let thunk = State {
+                    height,
+                    context: Context::Thunk(height),
+                };
+                return self.enter(ip + 1, thunk);
+            }
+            Op::EndDefer => {
+                return match context {
+                    Context::Thunk(entry) if entry == height => Ok(()),
+                    _ => Err("Unbalanced deferred expression".into()),
+                };
+            }
+            Op::AssertFailed(keyword, source, has_msg, has_operands) => {
+                self.token(keyword)?;
+                self.constant(source)?;
+                return pop(has_msg as u32 + 2 * has_operands as u32);
+            }
+            Op::Require(i) | Op::Ensure(i) => {
+                let is_require = matches!(chunk.code[ip], Op::Require(_));
+                if proto.name.is_none() || is_require && self.is_script {
+                    return Err("Contract outside of a function".into());
+                }
+                if i as usize >= proto.conditions.len() {
+                    return Err("Invalid condition index".into());
+                }
+                pop(1)?;
+            }
+        }
+
+        self.enter(ip + 1, state(height))
+    }
+
+    fn constant(&self, i: u32) -> Result<(), String> {
+        match (i as usize) < self.proto.chunk.constants.len() {
+            true => Ok(()),
+            false => Err("Invalid constant index".into()),
+        }
+    }
+
+    fn token(&self, i: u32) -> Result<&TokenAstInfo, String> {
+        (self.proto.chunk.tokens)
+            .get(i as usize)
+            .ok_or_else(|| "Invalid token index".into())
+    }
+
+    fn name(&self, i: u32) -> Result<(), String> {
+        match self.token(i)?.name {
+            Some(_) => Ok(()),
+            None => Err("Token without a name".into()),
+        }
+    }
+
+    fn slot(&self, slot: u32, height: usize) -> Result<(), String> {
+        match (slot as usize) < height {
+            true => Ok(()),
+            false => Err("Invalid slot".into()),
+        }
+    }
+
+    fn upvalue(&self, i: u32) -> Result<(), String> {
+        match (i as usize) < self.proto.captures.len() {
+            true => Ok(()),
+            false => Err("Invalid upvalue index".into()),
+        }
+    }
+}